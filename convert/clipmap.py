@@ -3,7 +3,6 @@ from joblib import Parallel, delayed
 import numpy as np
 import pyktx
 import cv2
-import pathlib
 import argparse
 from PIL import Image
 Image.MAX_IMAGE_PIXELS = 933120000
@@ -79,37 +78,42 @@ def rotate_opencv(heightmap, angle_deg, order=1, reshape=True):
     )
 
 
-def horizon_map_tangents_azimuth(heightmap, angle, dest):
+def horizon_map_tangents_azimuth(heightmap, angle):
     horizon = rotate_opencv(heightmap, angle, order=1, reshape=True)
     horizon = horizon_map_tangents(horizon)
-    # print(horizon.dtype)
     horizon = rotate_opencv(horizon, -angle, order=0, reshape=False)
     crop_y = (horizon.shape[0] - heightmap.shape[0]) // 2
     crop_x = (horizon.shape[1] - heightmap.shape[1]) // 2
-    horizon = horizon[crop_y:crop_y+heightmap.shape[0],
-                      crop_x:crop_x+heightmap.shape[1]]
-    print(f'{dest}/horizon_{angle}.npy')
-    np.save(f'{dest}/horizon_{angle}', horizon)
+    return horizon[crop_y:crop_y+heightmap.shape[0],
+                   crop_x:crop_x+heightmap.shape[1]].astype(np.float32)
 
 
-@jit
-def fft_compress_row(n_coeffs, row):
-    coeffs = np.fft.rfft(row, axis=0)
-    k = n_coeffs // 2
-    res = np.zeros((row.shape[1], n_coeffs+1))
-    res[:, :k+1] = coeffs[0:k+1].real.T
-    res[:, k+1:] = coeffs[1:k+1].imag.T
-    return res
+def map_tiles(shape, tile, border):
+    # Tiles of the map with the slices of the tile inside the bordered region it is traced in.
+    for y in range(0, shape[0], tile):
+        for x in range(0, shape[1], tile):
+            tile_slices = (slice(y, min(y + tile, shape[0])),
+                           slice(x, min(x + tile, shape[1])))
+            bordered = (slice(max(y - border, 0), min(y + tile + border, shape[0])),
+                        slice(max(x - border, 0), min(x + tile + border, shape[1])))
+            inner = tuple(slice(t.start - b.start, t.stop - b.start)
+                          for t, b in zip(tile_slices, bordered))
+            yield tile_slices, bordered, inner
 
 
-@jit(parallel=True)
-def fft_compress(horizons, n_coeffs):
-    height, width = horizons[0].shape
-    res = np.zeros((height, width, n_coeffs+1), dtype=np.float32)
-    for y in prange(height):
-        row = stack([horizon[y] for horizon in horizons])
-        res[y] = fft_compress_row(n_coeffs, row)
-    return res
+def sector_accumulate(res, horizon, angle, azimuths):
+    sector = angle * res.shape[2] // azimuths
+    np.maximum(res[:, :, sector], horizon, out=res[:, :, sector])
+
+
+def fft_accumulate(res, horizon, angle, azimuths):
+    # numpy.fft.rfft: X_k = sum x_n * exp(-2 pi i k n / N)
+    k = (res.shape[2] - 1) // 2
+    for i in range(k+1):
+        phase = 2 * np.pi * i * angle / azimuths
+        res[:, :, i] += horizon * np.float32(np.cos(phase))
+        if i > 0:
+            res[:, :, k+i] -= horizon * np.float32(np.sin(phase))
 
 
 if __name__ == "__main__":
@@ -145,24 +149,48 @@ if __name__ == "__main__":
 
     def cmd_horizon(args):
         print(f"Horizon map {args.width}x{args.height}, coeffs={args.coeffs}")
+        if args.sectors:
+            layers, accumulate = max(args.coeffs, 1), sector_accumulate
+        else:
+            layers, accumulate = args.coeffs+1, fft_accumulate
 
         azimuths = 360
-        dest = './horizons'
-
-        pathlib.Path(dest).mkdir(parents=True, exist_ok=True)
+        batch = 8
 
         heightmap = np.array(Image.open(args.filename).resize(
             (args.width, args.height)), dtype=np.float32) / 65535.0
 
+        # Every tile is traced with a border of the search distance around it, and the layers are
+        # accumulated batch by batch, so only a few azimuths of one tile are kept in memory.
         print('Computing horizon maps...')
-        Parallel(8)(delayed(horizon_map_tangents_azimuth)(
-            heightmap, angle, dest) for angle in range(azimuths))
+        horizonmap = np.zeros(
+            heightmap.shape + (layers,), dtype=np.float32)
+        tile = args.tile if args.tile > 0 else max(heightmap.shape)
+        tiles = list(map_tiles(heightmap.shape, tile, args.max_distance))
+        with Parallel(batch) as parallel:
+            for i, (tile_slices, bordered, inner) in enumerate(tiles):
+                region = heightmap[bordered]
+                for start in range(0, azimuths, batch):
+                    angles = range(start, min(start + batch, azimuths))
+                    horizons = parallel(delayed(horizon_map_tangents_azimuth)(
+                        region, angle) for angle in angles)
+                    for angle, horizon in zip(angles, horizons):
+                        accumulate(horizonmap[tile_slices],
+                                   horizon[inner], angle, azimuths)
+                print(f'{i + 1}/{len(tiles)} tiles')
         print('Done.')
 
-        print('Compressing...')
-        horizonmap = fft_compress([np.load(
-            f'{dest}/horizon_{angle}.npy', mmap_mode='r') for angle in range(azimuths)], args.coeffs)
-        print('Done.')
+        if args.packed:
+            # Four coefficients per RGBA16F layer.
+            channels = 4
+            vk_format = pyktx.VkFormat.VK_FORMAT_R16G16B16A16_SFLOAT
+            padding = -horizonmap.shape[2] % channels
+            horizonmap = np.pad(
+                horizonmap, ((0, 0), (0, 0), (0, padding))).astype(np.float16)
+        else:
+            channels = 1
+            vk_format = pyktx.VkFormat.VK_FORMAT_R32_SFLOAT
+        layers = horizonmap.shape[2] // channels
 
         print('Saving ktx2...')
         texture = pyktx.KtxTexture2.create(pyktx.KtxTextureCreateInfo(
@@ -172,21 +200,26 @@ if __name__ == "__main__":
             base_depth=1,
             num_dimensions=2,
             num_levels=1,
-            num_layers=horizonmap.shape[2],
+            num_layers=layers,
             num_faces=1,
             is_array=True,
-            vk_format=pyktx.VkFormat.VK_FORMAT_R32_SFLOAT,
+            vk_format=vk_format,
             generate_mipmaps=False,
         ), pyktx.KtxTextureCreateStorage.ALLOC)
-        for layer in range(horizonmap.shape[2]):
+        for layer in range(layers):
             texture.set_image_from_memory(
                 level=0,
                 layer=layer,
                 face_slice=0,
-                data=horizonmap[:, :, layer].tobytes('C'),
+                data=horizonmap[:, :, layer*channels:(layer+1)*channels].tobytes('C'),
             )
         horizon_filename = '.'.join(args.filename.split('.')[:-1])
-        horizon_filename += f'_horizon_{args.width}x{args.height}_{args.coeffs}.ktx2'
+        horizon_filename += f'_horizon_{args.width}x{args.height}_{args.coeffs}'
+        if args.packed:
+            horizon_filename += '_packed'
+        elif args.sectors:
+            horizon_filename += '_sectors'
+        horizon_filename += '.ktx2'
         texture.write_to_named_file(horizon_filename)
         print('Done.')
 
@@ -205,7 +238,17 @@ if __name__ == "__main__":
         'horizon', help='Create KTX2 horizon map')
     p_horizon.add_argument('width', type=int, help='Output width')
     p_horizon.add_argument('height', type=int, help='Output height')
-    p_horizon.add_argument('coeffs', type=int, help='Number of FFT coeffs')
+    p_horizon.add_argument(
+        'coeffs', type=int, help='Number of FFT coeffs, or of sectors with --sectors')
+    p_horizon.add_argument('--tile', type=int, default=2048,
+                           help='Size of the tiles traced at once, 0 traces the whole map')
+    p_horizon.add_argument('--max-distance', type=int, default=256,
+                           help='Horizon search distance around every tile in texels')
+    p_encoding = p_horizon.add_mutually_exclusive_group()
+    p_encoding.add_argument('--packed', action='store_true',
+                            help='Pack four coeffs per RGBA16F layer')
+    p_encoding.add_argument('--sectors', action='store_true',
+                            help='Store the highest horizon per azimuth sector')
     p_horizon.set_defaults(func=cmd_horizon)
 
     args = parser.parse_args()
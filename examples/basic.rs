@@ -11,7 +11,7 @@ use bevy::{
     prelude::*,
 };
 
-use bevy_clipmap::{Clipmap, ClipmapPlugin};
+use bevy_clipmap::{Clipmap, ClipmapHorizon, ClipmapPlugin};
 
 fn main() {
     App::new()
@@ -77,16 +77,21 @@ fn setup(
                 settings.is_srgb = false;
             },
         ),
-        horizon: asset_server.load_with_settings(
-            "heightmap_horizon_512x512_8.ktx2",
-            |settings: &mut ImageLoaderSettings| {
-                settings.is_srgb = false;
-            },
-        ),
-        horizon_coeffs: 8,
+        horizon: Some(ClipmapHorizon {
+            texture: asset_server.load_with_settings(
+                "heightmap_horizon_512x512_8.ktx2",
+                |settings: &mut ImageLoaderSettings| {
+                    settings.is_srgb = false;
+                },
+            ),
+            coeffs: 8,
+            ao: 1.0,
+            ..Default::default()
+        }),
         min: -1312.5,
         max: 1312.5,
         wireframe: false,
+        ..Default::default()
     });
 }
 
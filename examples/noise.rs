@@ -0,0 +1,52 @@
+use bevy::{
+    camera_controller::free_camera::{FreeCamera, FreeCameraPlugin},
+    light::light_consts::lux,
+    prelude::*,
+};
+
+use bevy_clipmap::{Clipmap, ClipmapHeightSource, ClipmapPlugin, NoiseKind, NoiseSource};
+
+fn main() {
+    App::new()
+        .add_plugins(DefaultPlugins)
+        .add_plugins(FreeCameraPlugin)
+        .add_plugins(ClipmapPlugin)
+        .add_systems(Startup, setup)
+        .run();
+}
+
+fn setup(mut commands: Commands) {
+    let target = commands
+        .spawn((
+            Camera3d::default(),
+            Transform::from_xyz(0.0, 1500.0, 0.0).looking_at(Vec3::new(1.0, 1400.0, 0.0), Vec3::Y),
+            FreeCamera {
+                walk_speed: 500.0,
+                run_speed: 1000.0,
+                ..Default::default()
+            },
+        ))
+        .id();
+
+    commands.spawn((
+        DirectionalLight {
+            illuminance: lux::AMBIENT_DAYLIGHT,
+            ..Default::default()
+        },
+        Transform::from_xyz(1.0, 1.0, 0.5).looking_at(Vec3::ZERO, Vec3::Y),
+    ));
+
+    commands.spawn((
+        Clipmap {
+            texel_size: 4.0,
+            target,
+            min: 0.0,
+            max: 2000.0,
+            ..Default::default()
+        },
+        ClipmapHeightSource::new(NoiseSource {
+            kind: NoiseKind::Ridged,
+            ..Default::default()
+        }),
+    ));
+}
@@ -0,0 +1,131 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use bevy::math::UVec2;
+use bevy_clipmap::{HorizonEncoding, HorizonMap, write_r16_ktx2};
+use clap::{Parser, Subcommand};
+use image::{ImageBuffer, Luma, imageops::FilterType};
+
+/// Heightmap processing tool for the bevy-clipmap plugin
+#[derive(Parser)]
+struct Args {
+    /// 16-bit PNG heightmap
+    filename: PathBuf,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Convert the heightmap to KTX2
+    Ktx {
+        /// Output width
+        width: u32,
+        /// Output height
+        height: u32,
+    },
+    /// Create KTX2 horizon map
+    Horizon {
+        /// Output width
+        width: u32,
+        /// Output height
+        height: u32,
+        /// Number of FFT coefficients, or of sectors with --sectors
+        coeffs: u32,
+        /// Pack four coefficients per RGBA16F layer
+        #[arg(long)]
+        packed: bool,
+        /// Store the highest horizon per azimuth sector instead of FFT coefficients
+        #[arg(long, conflicts_with = "packed")]
+        sectors: bool,
+    },
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+    match args.command {
+        Command::Ktx { width, height } => {
+            println!(
+                "Convert {} to KTX2 {width}x{height}",
+                args.filename.display()
+            );
+            let heightmap = load(&args.filename, width, height)?;
+            let path = output(&args.filename, &format!("_{width}x{height}.ktx2"));
+            let mut writer = BufWriter::new(File::create(path)?);
+            write_r16_ktx2(&mut writer, UVec2::new(width, height), heightmap.as_raw())?;
+            writer.flush()?;
+            println!("Done.");
+        }
+        Command::Horizon {
+            width,
+            height,
+            coeffs,
+            packed,
+            sectors,
+        } => {
+            println!("Horizon map {width}x{height}, coeffs={coeffs}");
+            let heights = load(&args.filename, width, height)?
+                .pixels()
+                .map(|pixel| pixel.0[0] as f32 / 65535.0)
+                .collect::<Vec<_>>();
+
+            println!("Computing horizon maps...");
+            let size = UVec2::new(width, height);
+            let horizon = if sectors {
+                HorizonMap::bake_sectors(&heights, size, coeffs)
+            } else {
+                HorizonMap::bake(&heights, size, coeffs)
+            };
+            println!("Done.");
+
+            println!("Saving ktx2...");
+            let (encoding, suffix) = if packed {
+                (HorizonEncoding::PackedFft, "_packed")
+            } else if sectors {
+                (HorizonEncoding::Sectors, "_sectors")
+            } else {
+                (HorizonEncoding::Fft, "")
+            };
+            let path = output(
+                &args.filename,
+                &format!("_horizon_{width}x{height}_{coeffs}{suffix}.ktx2"),
+            );
+            let mut writer = BufWriter::new(File::create(path)?);
+            horizon.write_ktx2(&mut writer, encoding)?;
+            writer.flush()?;
+            println!("Done.");
+        }
+    }
+    Ok(())
+}
+
+/// Loads the heightmap as 16-bit grayscale resized to the given size.
+/// Catmull-Rom is the kernel of the bicubic resize of `convert/clipmap.py`, but Pillow rounds the
+/// heights between its horizontal and vertical pass, so resized heights can differ by one.
+fn load(
+    path: &Path,
+    width: u32,
+    height: u32,
+) -> Result<ImageBuffer<Luma<u16>, Vec<u16>>, image::ImageError> {
+    let image = image::open(path)?.into_luma16();
+    if image.dimensions() == (width, height) {
+        return Ok(image);
+    }
+    Ok(image::imageops::resize(
+        &image,
+        width,
+        height,
+        FilterType::CatmullRom,
+    ))
+}
+
+/// Input path without the extension followed by the suffix.
+fn output(path: &Path, suffix: &str) -> PathBuf {
+    let mut output = path.with_extension("").into_os_string();
+    output.push(suffix);
+    output.into()
+}
@@ -0,0 +1,158 @@
+use bevy::prelude::*;
+
+use crate::{Clipmap, ClipmapHeightQuery};
+
+/// Requests collider data for the terrain around the clipmap target.
+/// The result is stored in [`ClipmapHeightfield`] on the same entity.
+/// Heights come from the full resolution heightmap, while levels whose vertex spacing is larger
+/// than [`Clipmap::texel_size`] render coarser mips, so far from the target the collider can
+/// differ from the visible surface.
+#[derive(Component, Clone, Debug)]
+#[require(ClipmapHeightfield)]
+pub struct ClipmapCollider {
+    /// Number of height samples along each side.
+    pub resolution: u32,
+
+    /// Distance between neighbour samples in world units.
+    pub spacing: f32,
+
+    /// The heightfield is rebuilt once the target moves this far from its center.
+    pub rebuild_distance: f32,
+}
+
+impl Default for ClipmapCollider {
+    fn default() -> Self {
+        Self {
+            resolution: 129,
+            spacing: 1.0,
+            rebuild_distance: 16.0,
+        }
+    }
+}
+
+/// Backend-neutral heightfield collider data.
+#[derive(Component, Clone, Debug, Default)]
+pub struct ClipmapHeightfield {
+    /// World heights in meters, row-major with rows along Z.
+    pub heights: Vec<f32>,
+
+    /// Number of samples along X and Z.
+    pub size: UVec2,
+
+    /// Distance between neighbour samples along X and Z.
+    pub scale: Vec2,
+
+    /// World position of the first sample, Y is always zero.
+    pub origin: Vec3,
+}
+
+impl ClipmapHeightfield {
+    /// Height of the sample at the given column and row.
+    pub fn get(&self, x: u32, z: u32) -> f32 {
+        self.heights[(z * self.size.x + x) as usize]
+    }
+
+    /// Size of the heightfield in world units.
+    pub fn extent(&self) -> Vec2 {
+        self.size.saturating_sub(UVec2::ONE).as_vec2() * self.scale
+    }
+
+    /// World position of the heightfield center, Y is always zero.
+    pub fn center(&self) -> Vec3 {
+        self.origin + (self.extent() / 2.0).extend(0.0).xzy()
+    }
+}
+
+pub(crate) fn update_heightfields(
+    transforms: Query<&GlobalTransform>,
+    heights: ClipmapHeightQuery,
+    mut clipmaps: Query<(Entity, &Clipmap, &ClipmapCollider, &mut ClipmapHeightfield)>,
+) {
+    for (entity, clipmap, collider, mut heightfield) in &mut clipmaps {
+        let Ok(target) = transforms.get(clipmap.target) else {
+            continue;
+        };
+        let Some(sampler) = heights.sampler(entity) else {
+            continue;
+        };
+
+        let snap = collider.rebuild_distance.max(collider.spacing);
+        let center = ((target.translation().xz() / snap).round() * snap)
+            .extend(0.0)
+            .xzy();
+        let size = UVec2::splat(collider.resolution.max(2));
+        let scale = Vec2::splat(collider.spacing);
+        if !heightfield.heights.is_empty()
+            && heightfield.size == size
+            && heightfield.scale == scale
+            && heightfield.center().distance(center) < snap / 2.0
+        {
+            continue;
+        }
+
+        let origin = center - ((size - 1).as_vec2() * scale / 2.0).extend(0.0).xzy();
+        let heights = (0..size.y)
+            .flat_map(|z| (0..size.x).map(move |x| UVec2::new(x, z)))
+            .map(|xz| sampler.height(origin.xz() + xz.as_vec2() * scale))
+            .collect();
+
+        *heightfield = ClipmapHeightfield {
+            heights,
+            size,
+            scale,
+            origin,
+        };
+    }
+}
+
+#[cfg(feature = "avian3d")]
+mod avian {
+    use avian3d::prelude::{Collider, RigidBody};
+    use bevy::prelude::*;
+
+    use super::ClipmapHeightfield;
+
+    /// Child entity holding the physics collider.
+    #[derive(Component)]
+    pub(crate) struct ClipmapColliderBody(Entity);
+
+    pub(crate) fn update_colliders(
+        mut commands: Commands,
+        heightfields: Query<
+            (Entity, &ClipmapHeightfield, Option<&ClipmapColliderBody>),
+            Changed<ClipmapHeightfield>,
+        >,
+    ) {
+        for (entity, heightfield, body) in heightfields {
+            if heightfield.heights.is_empty() {
+                continue;
+            }
+
+            let heights = (0..heightfield.size.x)
+                .map(|x| {
+                    (0..heightfield.size.y)
+                        .map(|z| heightfield.get(x, z))
+                        .collect()
+                })
+                .collect();
+            let extent = heightfield.extent();
+            let collider = Collider::heightfield(heights, Vec3::new(extent.x, 1.0, extent.y));
+            let transform = Transform::from_translation(heightfield.center());
+
+            match body {
+                Some(body) => {
+                    commands.entity(body.0).insert((collider, transform));
+                }
+                None => {
+                    let body = commands
+                        .spawn((RigidBody::Static, collider, transform, ChildOf(entity)))
+                        .id();
+                    commands.entity(entity).insert(ClipmapColliderBody(body));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(feature = "avian3d")]
+pub(crate) use avian::update_colliders;
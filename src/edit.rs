@@ -0,0 +1,282 @@
+use bevy::prelude::*;
+
+use crate::{
+    Clipmap, ClipmapHeightfield,
+    height::{read_texel, texel_bytes, write_texel},
+    history::ClipmapHistory,
+    horizon_bake::ClipmapHorizonBake,
+    levels::ClipmapLevels,
+    mip::{downsample_region, mip_size},
+    noise::gradient_noise,
+    upload::ImageUploads,
+};
+
+/// What a [`ClipmapBrush`] does to the terrain.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BrushMode {
+    /// Raises the terrain by `strength` meters at the brush center.
+    Raise,
+
+    /// Lowers the terrain by `strength` meters at the brush center.
+    Lower,
+
+    /// Blends the terrain toward the average of the neighbour texels by `strength` in `0..1`.
+    Smooth,
+
+    /// Blends the terrain toward the world height in meters by `strength` in `0..1`.
+    Flatten(f32),
+
+    /// Adds noise of the given wavelength in world units, `strength` meters at most.
+    Noise(f32),
+}
+
+/// One stroke of a sculpting brush applied to the heightmap of a clipmap.
+/// Heights outside of `Clipmap::min` and `Clipmap::max` expand the bounds.
+#[derive(Message, Clone, Copy, Debug)]
+pub struct ClipmapBrush {
+    /// The clipmap entity.
+    pub clipmap: Entity,
+
+    /// What the brush does.
+    pub mode: BrushMode,
+
+    /// World XZ of the brush center.
+    pub position: Vec2,
+
+    /// Radius of the brush in world units.
+    pub radius: f32,
+
+    /// Fraction of the radius over which the brush fades out.
+    pub falloff: f32,
+
+    /// Amount of the effect, see [`BrushMode`].
+    pub strength: f32,
+}
+
+impl ClipmapBrush {
+    /// Weight of the brush at the distance from its center.
+    fn weight(&self, distance: f32) -> f32 {
+        let inner = self.radius * (1.0 - self.falloff.clamp(0.0, 1.0));
+        if distance <= inner {
+            return 1.0;
+        }
+        let t = ((distance - inner) / (self.radius - inner)).clamp(0.0, 1.0);
+        1.0 - t * t * (3.0 - 2.0 * t)
+    }
+}
+
+/// Clipmap components updated by a brush stroke.
+type BrushedClipmap = (
+    &'static mut Clipmap,
+    Option<&'static mut ClipmapHeightfield>,
+    Option<&'static mut ClipmapHistory>,
+    Option<&'static mut ClipmapHorizonBake>,
+);
+
+/// Applies the brush strokes to the clipmap heightmaps.
+/// Only the heightmaps of clipmaps without a height source can be edited.
+pub(crate) fn apply_brushes(
+    mut brushes: MessageReader<ClipmapBrush>,
+    mut images: ResMut<Assets<Image>>,
+    mut uploads: ResMut<ImageUploads>,
+    mut clipmaps: Query<BrushedClipmap, Without<ClipmapLevels>>,
+) {
+    for brush in brushes.read() {
+        let Ok((mut clipmap, heightfield, history, horizon)) = clipmaps.get_mut(brush.clipmap)
+        else {
+            continue;
+        };
+        let Some(rect) = apply_brush(
+            &mut clipmap,
+            &mut images,
+            &mut uploads,
+            history.map(Mut::into_inner),
+            horizon.map(Mut::into_inner),
+            brush,
+        ) else {
+            continue;
+        };
+
+        // Rebuild the collider data if the stroke touched it.
+        if let Some(mut heightfield) = heightfield {
+            let min = heightfield.origin.xz();
+            let max = min + heightfield.extent();
+            if rect.min.cmple(max).all() && rect.max.cmpge(min).all() {
+                heightfield.heights.clear();
+            }
+        }
+    }
+}
+
+/// Applies the brush, returns the world XZ rectangle it changed.
+fn apply_brush(
+    clipmap: &mut Clipmap,
+    images: &mut Assets<Image>,
+    uploads: &mut ImageUploads,
+    history: Option<&mut ClipmapHistory>,
+    horizon: Option<&mut ClipmapHorizonBake>,
+    brush: &ClipmapBrush,
+) -> Option<Rect> {
+    let image = images.get(&clipmap.heightmap)?;
+    let format = image.texture_descriptor.format;
+    let bytes = texel_bytes(format)?;
+    let data = image.data.as_deref()?;
+    let size = image.size();
+
+    let texel_to_world =
+        |texel: IVec2| (texel.as_vec2() - size.as_vec2() * 0.5) * clipmap.texel_size;
+    let world_to_texel = |xz: Vec2| xz / clipmap.texel_size + size.as_vec2() * 0.5;
+    let min = world_to_texel(brush.position - brush.radius)
+        .floor()
+        .as_ivec2()
+        .max(IVec2::ZERO);
+    let max = (world_to_texel(brush.position + brush.radius)
+        .ceil()
+        .as_ivec2()
+        + 1)
+    .min(size.as_ivec2());
+    if min.cmpge(max).any() {
+        return None;
+    }
+
+    let height = |texel: IVec2| {
+        let texel = texel.clamp(IVec2::ZERO, size.as_ivec2() - 1);
+        let i = (texel.y as usize * size.x as usize + texel.x as usize) * bytes;
+        read_texel(format, &data[i..i + bytes]).unwrap() * (clipmap.max - clipmap.min) + clipmap.min
+    };
+
+    let width = (max.x - min.x) as usize;
+    let heights = (min.y..max.y)
+        .flat_map(|y| (min.x..max.x).map(move |x| IVec2::new(x, y)))
+        .map(|texel| {
+            let h = height(texel);
+            let world = texel_to_world(texel);
+            let weight = brush.weight(world.distance(brush.position));
+            if weight <= 0.0 {
+                return h;
+            }
+            match brush.mode {
+                BrushMode::Raise => h + brush.strength * weight,
+                BrushMode::Lower => h - brush.strength * weight,
+                BrushMode::Smooth => {
+                    let average = [IVec2::X, IVec2::NEG_X, IVec2::Y, IVec2::NEG_Y]
+                        .into_iter()
+                        .map(|offset| height(texel + offset))
+                        .sum::<f32>()
+                        / 4.0;
+                    h.lerp(average, (brush.strength * weight).clamp(0.0, 1.0))
+                }
+                BrushMode::Flatten(target) => {
+                    h.lerp(target, (brush.strength * weight).clamp(0.0, 1.0))
+                }
+                BrushMode::Noise(wavelength) => {
+                    h + brush.strength * weight * gradient_noise(world / wavelength, 0)
+                }
+            }
+        })
+        .collect::<Vec<_>>();
+
+    // Expand the bounds and renormalize the whole heightmap if the stroke left them.
+    let new_min = heights.iter().copied().fold(clipmap.min, f32::min);
+    let new_max = heights.iter().copied().fold(clipmap.max, f32::max);
+    let renormalize = new_min < clipmap.min || new_max > clipmap.max;
+    let (changed_min, changed_max) = if renormalize {
+        (UVec2::ZERO, size)
+    } else {
+        (min.as_uvec2(), max.as_uvec2())
+    };
+    // The history only keeps the stroke, the renormalization is replayed from the bounds.
+    let before = history.is_some().then(|| {
+        let image = images.get(&clipmap.heightmap).unwrap();
+        ClipmapHistory::capture(image, 0, min.as_uvec2(), max.as_uvec2())
+    });
+    let bounds_before = Vec2::new(clipmap.min, clipmap.max);
+
+    let image = if renormalize {
+        let image = images.get_mut(&clipmap.heightmap)?;
+        renormalize_heights(image, bounds_before, Vec2::new(new_min, new_max));
+        clipmap.min = new_min;
+        clipmap.max = new_max;
+        image
+    } else {
+        images.get_mut_untracked(&clipmap.heightmap)?
+    };
+
+    let data = image.data.as_mut()?;
+    for (i, h) in heights.iter().enumerate() {
+        let texel = min + IVec2::new((i % width) as i32, (i / width) as i32);
+        let offset = (texel.y as usize * size.x as usize + texel.x as usize) * bytes;
+        let h = (h - clipmap.min) / (clipmap.max - clipmap.min);
+        write_texel(format, h, &mut data[offset..offset + bytes]);
+    }
+    refresh_region(
+        &clipmap.heightmap,
+        image,
+        uploads,
+        0,
+        changed_min,
+        changed_max,
+    );
+    if let Some(horizon) = horizon {
+        horizon.mark_dirty(changed_min, changed_max);
+    }
+
+    if let (Some(history), Some(before)) = (history, before) {
+        history.record_height(
+            image,
+            min.as_uvec2(),
+            max.as_uvec2(),
+            before,
+            [bounds_before, Vec2::new(clipmap.min, clipmap.max)],
+        );
+    }
+
+    Some(Rect::from_center_half_size(
+        brush.position,
+        Vec2::splat(brush.radius),
+    ))
+}
+
+/// Maps the normalized heights of the whole heightmap from the `from` bounds to the `to` bounds.
+pub(crate) fn renormalize_heights(image: &mut Image, from: Vec2, to: Vec2) {
+    let format = image.texture_descriptor.format;
+    let (Some(bytes), Some(data)) = (texel_bytes(format), image.data.as_mut()) else {
+        return;
+    };
+    let scale = (from.y - from.x) / (to.y - to.x);
+    let offset = (from.x - to.x) / (to.y - to.x);
+    for texel in data.chunks_exact_mut(bytes) {
+        write_texel(
+            format,
+            read_texel(format, texel).unwrap() * scale + offset,
+            texel,
+        );
+    }
+}
+
+/// Rebuilds the mips over the changed region of the first mip and queues the region uploads.
+/// Mips of texture arrays and of formats without CPU filtering are left as is.
+pub(crate) fn refresh_region(
+    handle: &Handle<Image>,
+    image: &mut Image,
+    uploads: &mut ImageUploads,
+    layer: u32,
+    min: UVec2,
+    max: UVec2,
+) {
+    uploads.push(handle, image, 0, layer, min, max);
+    if image.texture_descriptor.size.depth_or_array_layers > 1
+        || texel_bytes(image.texture_descriptor.format).is_none()
+    {
+        return;
+    }
+
+    let size = image.size();
+    let (mut min, mut max) = (min, max);
+    for mip in 1..image.texture_descriptor.mip_level_count {
+        min /= 2;
+        max = ((max - 1) / 2 + 1).min(mip_size(size, mip));
+        downsample_region(image, mip, min, max);
+        uploads.push(handle, image, mip, layer, min, max);
+    }
+}
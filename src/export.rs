@@ -0,0 +1,215 @@
+use std::{
+    error::Error,
+    fmt,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+use bevy::prelude::*;
+
+use crate::{
+    Clipmap,
+    height::{read_texel, texel_bytes},
+    ktx2::{R16_UNORM, write_ktx2},
+};
+
+/// Error while exporting a heightmap.
+#[derive(Debug)]
+pub enum ExportError {
+    /// The image is not loaded or has no CPU data.
+    MissingData,
+
+    /// The texture format of the image can't be read.
+    UnsupportedFormat,
+
+    /// The file extension is neither `ktx2` nor `png`, PNG export needs the `png` feature.
+    UnsupportedExtension,
+
+    Io(io::Error),
+
+    /// The PNG encoder failed.
+    Png(Box<dyn Error + Send + Sync>),
+}
+
+impl fmt::Display for ExportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingData => write!(f, "heightmap has no CPU data"),
+            Self::UnsupportedFormat => write!(f, "unsupported heightmap format"),
+            Self::UnsupportedExtension => write!(f, "unsupported file extension"),
+            Self::Io(error) => write!(f, "{error}"),
+            Self::Png(error) => write!(f, "{error}"),
+        }
+    }
+}
+
+impl Error for ExportError {}
+
+impl From<io::Error> for ExportError {
+    fn from(error: io::Error) -> Self {
+        Self::Io(error)
+    }
+}
+
+/// First mip of the heightmap as 16-bit normalized heights, row by row.
+pub fn heightmap_u16(image: &Image) -> Result<Vec<u16>, ExportError> {
+    let format = image.texture_descriptor.format;
+    let bytes = texel_bytes(format).ok_or(ExportError::UnsupportedFormat)?;
+    let data = image.data.as_deref().ok_or(ExportError::MissingData)?;
+    let texels = image.size().element_product() as usize;
+    Ok(data[..texels * bytes]
+        .chunks_exact(bytes)
+        .map(|texel| (read_texel(format, texel).unwrap().clamp(0.0, 1.0) * 65535.0).round() as u16)
+        .collect())
+}
+
+/// Writes the heightmap as an R16_UNORM KTX2 file, the same layout `convert/clipmap.py ktx` produces.
+pub fn write_heightmap_ktx2(writer: &mut impl Write, image: &Image) -> Result<(), ExportError> {
+    write_r16_ktx2(writer, image.size(), &heightmap_u16(image)?)?;
+    Ok(())
+}
+
+/// Writes 16-bit normalized heights given row by row as an R16_UNORM KTX2 file.
+pub fn write_r16_ktx2(writer: &mut impl Write, size: UVec2, heights: &[u16]) -> io::Result<()> {
+    let data = heights
+        .iter()
+        .flat_map(|height| height.to_le_bytes())
+        .collect::<Vec<_>>();
+    write_ktx2(writer, &R16_UNORM, size, &data)
+}
+
+/// Writes the heightmap as a 16-bit grayscale PNG.
+#[cfg(feature = "png")]
+pub fn write_heightmap_png(writer: &mut impl Write, image: &Image) -> Result<(), ExportError> {
+    let data = heightmap_u16(image)?
+        .into_iter()
+        .flat_map(u16::to_be_bytes)
+        .collect::<Vec<_>>();
+    let size = image.size();
+    let mut encoder = png::Encoder::new(writer, size.x, size.y);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Sixteen);
+    encoder
+        .write_header()
+        .and_then(|mut writer| writer.write_image_data(&data))
+        .map_err(|error| ExportError::Png(error.into()))
+}
+
+/// Saves the heightmap to a `.ktx2` or `.png` file depending on the extension.
+/// PNG files need the `png` feature.
+pub fn save_heightmap(image: &Image, path: impl AsRef<Path>) -> Result<(), ExportError> {
+    let path = path.as_ref();
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_ascii_lowercase);
+    let write: fn(&mut BufWriter<File>, &Image) -> Result<(), ExportError> =
+        match extension.as_deref() {
+            Some("ktx2") => write_heightmap_ktx2,
+            #[cfg(feature = "png")]
+            Some("png") => write_heightmap_png,
+            _ => return Err(ExportError::UnsupportedExtension),
+        };
+    let mut writer = BufWriter::new(File::create(path)?);
+    write(&mut writer, image)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Saves the current heightmap of the clipmap, including runtime edits.
+pub fn save_clipmap_heightmap(
+    clipmap: &Clipmap,
+    images: &Assets<Image>,
+    path: impl AsRef<Path>,
+) -> Result<(), ExportError> {
+    let image = images
+        .get(&clipmap.heightmap)
+        .ok_or(ExportError::MissingData)?;
+    save_heightmap(image, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::{
+        asset::RenderAssetUsages,
+        render::render_resource::{Extent3d, TextureDimension, TextureFormat},
+    };
+
+    use super::*;
+
+    fn heightmap() -> Image {
+        let data = (0..12u16).flat_map(|i| (i * 5000).to_le_bytes()).collect();
+        Image::new(
+            Extent3d {
+                width: 4,
+                height: 3,
+                depth_or_array_layers: 1,
+            },
+            TextureDimension::D2,
+            data,
+            TextureFormat::R16Unorm,
+            RenderAssetUsages::all(),
+        )
+    }
+
+    #[test]
+    fn ktx2_holds_heights() {
+        let mut file = vec![];
+        write_heightmap_ktx2(&mut file, &heightmap()).unwrap();
+        let heights = file[file.len() - 24..]
+            .chunks_exact(2)
+            .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+            .collect::<Vec<_>>();
+        assert_eq!(heights, (0..12).map(|i| i * 5000).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn ktx2_matches_python_tool() {
+        // Written by `convert/clipmap.py ktx`.
+        let python = std::fs::read(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/assets/heightmap_1024x1024.ktx2"
+        ))
+        .unwrap();
+        let data_offset = u64::from_le_bytes(python[80..88].try_into().unwrap()) as usize;
+        let heights = python[data_offset..]
+            .chunks_exact(2)
+            .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+            .collect::<Vec<_>>();
+        let mut file = vec![];
+        write_r16_ktx2(&mut file, UVec2::splat(1024), &heights).unwrap();
+        assert!(file == python);
+    }
+
+    #[cfg(feature = "png")]
+    #[test]
+    fn png_round_trip() {
+        let image = heightmap();
+        let mut file = vec![];
+        write_heightmap_png(&mut file, &image).unwrap();
+
+        let mut reader = png::Decoder::new(io::Cursor::new(file))
+            .read_info()
+            .unwrap();
+        let mut data = vec![0; reader.output_buffer_size().unwrap()];
+        let info = reader.next_frame(&mut data).unwrap();
+        assert_eq!((info.width, info.height), (4, 3));
+        assert_eq!(info.color_type, png::ColorType::Grayscale);
+        assert_eq!(info.bit_depth, png::BitDepth::Sixteen);
+        let heights = data
+            .chunks_exact(2)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+            .collect::<Vec<_>>();
+        assert_eq!(heights, heightmap_u16(&image).unwrap());
+    }
+
+    #[test]
+    fn unsupported_extension() {
+        let path = std::env::temp_dir().join("bevy_clipmap_heightmap.raw");
+        assert!(matches!(
+            save_heightmap(&heightmap(), path),
+            Err(ExportError::UnsupportedExtension)
+        ));
+    }
+}
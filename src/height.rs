@@ -0,0 +1,283 @@
+use bevy::{ecs::system::SystemParam, prelude::*, render::render_resource::TextureFormat};
+
+use crate::{Clipmap, levels::ClipmapLevels};
+
+/// Terrain height and normal at some world position.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ClipmapSample {
+    /// World height in meters.
+    pub height: f32,
+
+    /// World space normal.
+    pub normal: Vec3,
+}
+
+/// CPU view of a clipmap heightmap.
+/// Maps world XZ to texels the same way `terrain.wgsl` does.
+pub struct HeightmapSampler<'a> {
+    data: HeightData<'a>,
+    size: UVec2,
+    texel_size: f32,
+    min: f32,
+    max: f32,
+}
+
+impl<'a> HeightmapSampler<'a> {
+    /// Returns `None` if the image has no CPU data or its format is not supported.
+    pub fn new(clipmap: &Clipmap, image: &'a Image) -> Option<Self> {
+        let format = image.texture_descriptor.format;
+        texel_bytes(format)?;
+        Some(Self {
+            data: HeightData::Image {
+                data: image.data.as_deref()?,
+                format,
+            },
+            size: image.size(),
+            texel_size: clipmap.texel_size,
+            min: clipmap.min,
+            max: clipmap.max,
+        })
+    }
+
+    /// Sampler over the resident part of streamed per-level textures.
+    /// Returns `None` while the size of the height source is unknown.
+    pub(crate) fn from_levels(
+        clipmap: &Clipmap,
+        levels: &'a ClipmapLevels,
+        image: &'a Image,
+    ) -> Option<Self> {
+        if levels.size.cmpeq(UVec2::ZERO).any() {
+            return None;
+        }
+        Some(Self {
+            data: HeightData::Levels { levels, image },
+            size: levels.size,
+            texel_size: clipmap.texel_size,
+            min: clipmap.min,
+            max: clipmap.max,
+        })
+    }
+
+    /// Size of the heightmap in world units.
+    pub fn world_size(&self) -> Vec2 {
+        self.size.as_vec2() * self.texel_size
+    }
+
+    /// Size of the heightmap in texels.
+    pub fn size(&self) -> UVec2 {
+        self.size
+    }
+
+    /// Size of one texel in world units.
+    pub fn texel_size(&self) -> f32 {
+        self.texel_size
+    }
+
+    /// Normalized height of the texel, coordinates are clamped to the heightmap.
+    pub fn texel(&self, x: i32, y: i32) -> f32 {
+        let x = x.clamp(0, self.size.x as i32 - 1);
+        let y = y.clamp(0, self.size.y as i32 - 1);
+        match self.data {
+            HeightData::Image { data, format } => {
+                let bytes = texel_bytes(format).unwrap();
+                let offset = (y as usize * self.size.x as usize + x as usize) * bytes;
+                read_texel(format, &data[offset..offset + bytes]).unwrap()
+            }
+            HeightData::Levels { levels, image } => levels.height(image, IVec2::new(x, y)),
+        }
+    }
+
+    /// World XZ to texel space.
+    pub fn world_to_texel(&self, xz: Vec2) -> Vec2 {
+        (xz / self.world_size() + 0.5) * self.size.as_vec2()
+    }
+
+    /// Texel space to world XZ.
+    pub fn texel_to_world(&self, texel: Vec2) -> Vec2 {
+        (texel / self.size.as_vec2() - 0.5) * self.world_size()
+    }
+
+    /// Converts normalized height to meters.
+    pub fn denormalize(&self, height: f32) -> f32 {
+        height * (self.max - self.min) + self.min
+    }
+
+    /// Bilinearly filtered normalized height, same as `height_bilinear` at mip 0 in the shader.
+    pub fn height_normalized(&self, xz: Vec2) -> f32 {
+        let pos = self.world_to_texel(xz);
+        let p0 = pos.floor();
+        let f = pos - p0;
+        let (x, y) = (p0.x as i32, p0.y as i32);
+
+        let h00 = self.texel(x, y);
+        let h10 = self.texel(x + 1, y);
+        let h01 = self.texel(x, y + 1);
+        let h11 = self.texel(x + 1, y + 1);
+
+        let hx0 = h00 + (h10 - h00) * f.x;
+        let hx1 = h01 + (h11 - h01) * f.x;
+
+        hx0 + (hx1 - hx0) * f.y
+    }
+
+    /// World height in meters.
+    pub fn height(&self, xz: Vec2) -> f32 {
+        self.denormalize(self.height_normalized(xz))
+    }
+
+    /// World space normal, built from central differences like in the fragment shader.
+    pub fn normal(&self, xz: Vec2) -> Vec3 {
+        let step = self.texel_size;
+        let h_r = self.height_normalized(xz + Vec2::new(step, 0.0));
+        let h_l = self.height_normalized(xz - Vec2::new(step, 0.0));
+        let h_t = self.height_normalized(xz + Vec2::new(0.0, step));
+        let h_b = self.height_normalized(xz - Vec2::new(0.0, step));
+
+        let scale = (self.max - self.min) / (2.0 * self.texel_size);
+        let dh_dx = (h_r - h_l) * scale;
+        let dh_dy = (h_t - h_b) * scale;
+        Vec3::new(-dh_dx, 1.0, -dh_dy).normalize()
+    }
+
+    /// Height and normal at once.
+    pub fn sample(&self, xz: Vec2) -> ClipmapSample {
+        ClipmapSample {
+            height: self.height(xz),
+            normal: self.normal(xz),
+        }
+    }
+}
+
+enum HeightData<'a> {
+    Image {
+        data: &'a [u8],
+        format: TextureFormat,
+    },
+    Levels {
+        levels: &'a ClipmapLevels,
+        image: &'a Image,
+    },
+}
+
+/// Queries terrain height of clipmaps on the CPU.
+#[derive(SystemParam)]
+pub struct ClipmapHeightQuery<'w, 's> {
+    clipmaps: Query<'w, 's, (Entity, &'static Clipmap, Option<&'static ClipmapLevels>)>,
+    images: Res<'w, Assets<Image>>,
+}
+
+impl ClipmapHeightQuery<'_, '_> {
+    /// Sampler for the clipmap heightmap.
+    /// Returns `None` if the entity is not a clipmap or its heightmap is not loaded yet.
+    pub fn sampler(&self, clipmap: Entity) -> Option<HeightmapSampler<'_>> {
+        let (_, clipmap, levels) = self.clipmaps.get(clipmap).ok()?;
+        self.make_sampler(clipmap, levels)
+    }
+
+    /// Samplers of all clipmaps with loaded heightmaps.
+    pub fn iter(&self) -> impl Iterator<Item = (Entity, HeightmapSampler<'_>)> {
+        self.clipmaps
+            .iter()
+            .filter_map(|(entity, clipmap, levels)| {
+                Some((entity, self.make_sampler(clipmap, levels)?))
+            })
+    }
+
+    fn make_sampler<'a>(
+        &'a self,
+        clipmap: &Clipmap,
+        levels: Option<&'a ClipmapLevels>,
+    ) -> Option<HeightmapSampler<'a>> {
+        match levels {
+            Some(levels) => {
+                HeightmapSampler::from_levels(clipmap, levels, self.images.get(&levels.image)?)
+            }
+            None => HeightmapSampler::new(clipmap, self.images.get(&clipmap.heightmap)?),
+        }
+    }
+
+    /// World height in meters at world XZ.
+    pub fn height(&self, clipmap: Entity, xz: Vec2) -> Option<f32> {
+        Some(self.sampler(clipmap)?.height(xz))
+    }
+
+    /// World space normal at world XZ.
+    pub fn normal(&self, clipmap: Entity, xz: Vec2) -> Option<Vec3> {
+        Some(self.sampler(clipmap)?.normal(xz))
+    }
+
+    /// Height and normal at world XZ.
+    pub fn sample(&self, clipmap: Entity, xz: Vec2) -> Option<ClipmapSample> {
+        Some(self.sampler(clipmap)?.sample(xz))
+    }
+}
+
+/// Size of one texel in bytes for supported heightmap formats.
+pub(crate) fn texel_bytes(format: TextureFormat) -> Option<usize> {
+    match format {
+        TextureFormat::R8Unorm => Some(1),
+        TextureFormat::R16Unorm | TextureFormat::R16Uint | TextureFormat::R16Float => Some(2),
+        TextureFormat::R32Float => Some(4),
+        _ => None,
+    }
+}
+
+/// Decodes one texel into normalized height.
+pub(crate) fn read_texel(format: TextureFormat, bytes: &[u8]) -> Option<f32> {
+    match format {
+        TextureFormat::R8Unorm => Some(bytes[0] as f32 / u8::MAX as f32),
+        TextureFormat::R16Unorm | TextureFormat::R16Uint => {
+            Some(u16::from_le_bytes([bytes[0], bytes[1]]) as f32 / u16::MAX as f32)
+        }
+        TextureFormat::R16Float => Some(f16_to_f32(u16::from_le_bytes([bytes[0], bytes[1]]))),
+        TextureFormat::R32Float => Some(f32::from_le_bytes(bytes[..4].try_into().unwrap())),
+        _ => None,
+    }
+}
+
+/// Encodes normalized height into one texel.
+pub(crate) fn write_texel(format: TextureFormat, height: f32, bytes: &mut [u8]) {
+    match format {
+        TextureFormat::R8Unorm => {
+            bytes[0] = (height.clamp(0.0, 1.0) * u8::MAX as f32).round() as u8
+        }
+        TextureFormat::R16Unorm | TextureFormat::R16Uint => bytes[..2].copy_from_slice(
+            &((height.clamp(0.0, 1.0) * u16::MAX as f32).round() as u16).to_le_bytes(),
+        ),
+        TextureFormat::R16Float => bytes[..2].copy_from_slice(&f32_to_f16(height).to_le_bytes()),
+        TextureFormat::R32Float => bytes[..4].copy_from_slice(&height.to_le_bytes()),
+        _ => {}
+    }
+}
+
+fn f16_to_f32(bits: u16) -> f32 {
+    let sign = if bits & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exponent = ((bits >> 10) & 0x1f) as i32;
+    let mantissa = (bits & 0x3ff) as f32;
+    sign * match exponent {
+        0 => mantissa * 2f32.powi(-24),
+        0x1f if mantissa == 0.0 => f32::INFINITY,
+        0x1f => f32::NAN,
+        _ => (1.0 + mantissa / 1024.0) * 2f32.powi(exponent - 15),
+    }
+}
+
+fn f32_to_f16(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32 - 127 + 15;
+    let mantissa = bits & 0x7f_ffff;
+    if value.is_nan() {
+        sign | 0x7e00
+    } else if exponent >= 0x1f {
+        sign | 0x7c00
+    } else if exponent <= 0 {
+        if exponent < -10 {
+            return sign;
+        }
+        let mantissa = (mantissa | 0x80_0000) >> (1 - exponent);
+        sign | ((mantissa + 0x1000) >> 13) as u16
+    } else {
+        sign | (((exponent as u32) << 10) + ((mantissa + 0x1000) >> 13)) as u16
+    }
+}
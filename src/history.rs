@@ -0,0 +1,467 @@
+use std::collections::VecDeque;
+
+use bevy::prelude::*;
+
+use crate::{
+    Clipmap, ClipmapHeightfield, ClipmapLayers,
+    edit::{refresh_region, renormalize_heights},
+    horizon_bake::ClipmapHorizonBake,
+    mip::subresource_offset,
+    upload::ImageUploads,
+};
+
+/// A texture of a clipmap that can be edited.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClipmapTexture {
+    /// `Clipmap::heightmap`.
+    Height,
+
+    /// `Clipmap::color`.
+    Color,
+
+    /// `ClipmapLayers::control`.
+    Splat,
+}
+
+/// Texels of a region of one texture layer before and after an edit.
+struct RegionDelta {
+    texture: ClipmapTexture,
+    layer: u32,
+    min: UVec2,
+    max: UVec2,
+    before: Vec<u8>,
+    after: Vec<u8>,
+
+    /// `Clipmap::min` and `Clipmap::max` before and after a heightmap edit.
+    /// The texels outside of the region are renormalized between them instead of being stored.
+    bounds: Option<[Vec2; 2]>,
+}
+
+impl RegionDelta {
+    fn bytes(&self) -> usize {
+        self.before.len() + self.after.len()
+    }
+}
+
+/// Undo and redo stacks of the texture edits of a clipmap.
+/// Edits made with [`ClipmapBrush`](crate::ClipmapBrush) are recorded automatically,
+/// other tools record their edits with [`ClipmapHistory::record`].
+#[derive(Component)]
+pub struct ClipmapHistory {
+    /// Maximum memory used by the recorded edits in bytes, the oldest edits are dropped first.
+    /// The newest edit is always kept, even if it alone exceeds the budget.
+    pub budget: usize,
+
+    undo: VecDeque<RegionDelta>,
+    redo: Vec<RegionDelta>,
+    used: usize,
+}
+
+impl Default for ClipmapHistory {
+    fn default() -> Self {
+        Self::new(64 * 1024 * 1024)
+    }
+}
+
+impl ClipmapHistory {
+    pub fn new(budget: usize) -> Self {
+        Self {
+            budget,
+            undo: VecDeque::new(),
+            redo: vec![],
+            used: 0,
+        }
+    }
+
+    /// Copies the texels of the region of the texture layer, to be passed to `record` after the edit.
+    pub fn capture(image: &Image, layer: u32, min: UVec2, max: UVec2) -> Vec<u8> {
+        let Some((offset, width, bytes)) = region_layout(image, layer) else {
+            return vec![];
+        };
+        let data = image.data.as_deref().unwrap_or_default();
+        let row = (max.x - min.x) as usize * bytes;
+        (min.y..max.y)
+            .flat_map(|y| {
+                let start = offset + (y as usize * width + min.x as usize) * bytes;
+                &data[start..start + row]
+            })
+            .copied()
+            .collect()
+    }
+
+    /// Records an edit of the region `min..max` of the texture layer.
+    /// `before` is the result of [`ClipmapHistory::capture`] taken before the edit,
+    /// `image` already holds the edited texels.
+    pub fn record(
+        &mut self,
+        texture: ClipmapTexture,
+        image: &Image,
+        layer: u32,
+        min: UVec2,
+        max: UVec2,
+        before: Vec<u8>,
+    ) {
+        self.push(RegionDelta {
+            texture,
+            layer,
+            min,
+            max,
+            after: Self::capture(image, layer, min, max),
+            before,
+            bounds: None,
+        });
+    }
+
+    /// Records a heightmap edit that also changed the height bounds.
+    /// Only the region is stored, the rest of the heightmap is renormalized on undo and redo.
+    pub(crate) fn record_height(
+        &mut self,
+        image: &Image,
+        min: UVec2,
+        max: UVec2,
+        before: Vec<u8>,
+        bounds: [Vec2; 2],
+    ) {
+        self.push(RegionDelta {
+            texture: ClipmapTexture::Height,
+            layer: 0,
+            min,
+            max,
+            after: Self::capture(image, 0, min, max),
+            before,
+            bounds: Some(bounds),
+        });
+    }
+
+    /// Whether there is an edit to undo.
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+
+    /// Whether there is an edit to redo.
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    /// Forgets all recorded edits.
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+        self.used = 0;
+    }
+
+    fn push(&mut self, delta: RegionDelta) {
+        self.used -= self
+            .redo
+            .drain(..)
+            .map(|delta| delta.bytes())
+            .sum::<usize>();
+        self.used += delta.bytes();
+        self.undo.push_back(delta);
+        while self.used > self.budget && self.undo.len() > 1 {
+            let delta = self.undo.pop_front().unwrap();
+            self.used -= delta.bytes();
+        }
+        if self.used > self.budget {
+            warn!(
+                "Clipmap edit of {} bytes exceeds the history budget of {} bytes",
+                self.used, self.budget
+            );
+        }
+    }
+}
+
+/// Reverts the last recorded edit of the clipmap.
+#[derive(Message, Clone, Copy, Debug)]
+pub struct ClipmapUndo {
+    pub clipmap: Entity,
+}
+
+/// Applies the last reverted edit of the clipmap again.
+#[derive(Message, Clone, Copy, Debug)]
+pub struct ClipmapRedo {
+    pub clipmap: Entity,
+}
+
+/// Clipmap components restored by undo and redo.
+type HistoryClipmap = (
+    &'static mut Clipmap,
+    &'static mut ClipmapHistory,
+    Option<&'static ClipmapLayers>,
+    Option<&'static mut ClipmapHeightfield>,
+    Option<&'static mut ClipmapHorizonBake>,
+);
+
+pub(crate) fn apply_history(
+    mut undos: MessageReader<ClipmapUndo>,
+    mut redos: MessageReader<ClipmapRedo>,
+    mut images: ResMut<Assets<Image>>,
+    mut uploads: ResMut<ImageUploads>,
+    mut clipmaps: Query<HistoryClipmap>,
+) {
+    let undos = undos.read().map(|undo| (undo.clipmap, true));
+    let redos = redos.read().map(|redo| (redo.clipmap, false));
+    for (entity, undo) in undos.chain(redos) {
+        let Ok((mut clipmap, mut history, layers, heightfield, horizon)) = clipmaps.get_mut(entity)
+        else {
+            continue;
+        };
+        let delta = if undo {
+            history.undo.pop_back()
+        } else {
+            history.redo.pop()
+        };
+        let Some(delta) = delta else {
+            continue;
+        };
+
+        let handle = match delta.texture {
+            ClipmapTexture::Height => clipmap.heightmap.clone(),
+            ClipmapTexture::Color => clipmap.color.clone(),
+            ClipmapTexture::Splat => match layers {
+                Some(layers) => layers.control.clone(),
+                None => continue,
+            },
+        };
+        let (texels, bounds) = if undo {
+            (&delta.before, delta.bounds.map(|bounds| bounds[0]))
+        } else {
+            (&delta.after, delta.bounds.map(|bounds| bounds[1]))
+        };
+
+        let (mut min, mut max) = (delta.min, delta.max);
+        if let Some(image) = images.get_mut_untracked(&handle) {
+            let current = Vec2::new(clipmap.min, clipmap.max);
+            if let Some(bounds) = bounds
+                && bounds != current
+            {
+                renormalize_heights(image, current, bounds);
+                (min, max) = (UVec2::ZERO, image.size());
+                clipmap.min = bounds.x;
+                clipmap.max = bounds.y;
+            }
+            write_region(image, delta.layer, delta.min, delta.max, texels);
+            refresh_region(&handle, image, &mut uploads, delta.layer, min, max);
+        }
+        if delta.texture == ClipmapTexture::Height
+            && let Some(mut heightfield) = heightfield
+        {
+            heightfield.heights.clear();
+        }
+        if delta.texture == ClipmapTexture::Height
+            && let Some(mut horizon) = horizon
+        {
+            horizon.mark_dirty(min, max);
+        }
+
+        if undo {
+            history.redo.push(delta);
+        } else {
+            history.undo.push_back(delta);
+        }
+    }
+}
+
+/// Byte offset of the first mip of the layer, width of the mip and bytes per texel.
+fn region_layout(image: &Image, layer: u32) -> Option<(usize, usize, usize)> {
+    let bytes = image.texture_descriptor.format.block_copy_size(None)? as usize;
+    let offset = subresource_offset(image, layer, 0, bytes);
+    Some((offset, image.width() as usize, bytes))
+}
+
+fn write_region(image: &mut Image, layer: u32, min: UVec2, max: UVec2, texels: &[u8]) {
+    let Some((offset, width, bytes)) = region_layout(image, layer) else {
+        return;
+    };
+    let Some(data) = image.data.as_mut() else {
+        return;
+    };
+    let row = (max.x - min.x) as usize * bytes;
+    for (y, texels) in (min.y..max.y).zip(texels.chunks_exact(row)) {
+        let start = offset + (y as usize * width + min.x as usize) * bytes;
+        data[start..start + row].copy_from_slice(texels);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::{
+        asset::RenderAssetUsages,
+        render::render_resource::{Extent3d, TextureDataOrder, TextureDimension, TextureFormat},
+    };
+
+    use super::*;
+    use crate::{BrushMode, ClipmapBrush, edit::apply_brushes, height::read_texel};
+
+    const SIZE: u32 = 16;
+
+    fn app(budget: usize) -> (App, Entity) {
+        let mut app = App::new();
+        app.init_resource::<Assets<Image>>()
+            .init_resource::<ImageUploads>()
+            .add_message::<ClipmapBrush>()
+            .add_message::<ClipmapUndo>()
+            .add_message::<ClipmapRedo>()
+            .add_systems(Update, (apply_brushes, apply_history).chain());
+
+        let image = Image::new_fill(
+            Extent3d {
+                width: SIZE,
+                height: SIZE,
+                depth_or_array_layers: 1,
+            },
+            TextureDimension::D2,
+            &0.5f32.to_le_bytes(),
+            TextureFormat::R32Float,
+            RenderAssetUsages::all(),
+        );
+        let heightmap = app.world_mut().resource_mut::<Assets<Image>>().add(image);
+        let clipmap = app
+            .world_mut()
+            .spawn((
+                Clipmap {
+                    heightmap,
+                    min: 0.0,
+                    max: 10.0,
+                    ..default()
+                },
+                ClipmapHistory::new(budget),
+            ))
+            .id();
+        (app, clipmap)
+    }
+
+    fn brush(app: &mut App, clipmap: Entity, mode: BrushMode, strength: f32) {
+        app.world_mut().write_message(ClipmapBrush {
+            clipmap,
+            mode,
+            position: Vec2::ZERO,
+            radius: 2.0,
+            falloff: 0.5,
+            strength,
+        });
+        app.update();
+    }
+
+    /// World heights of all texels.
+    fn heights(app: &App, clipmap: Entity) -> Vec<f32> {
+        let clipmap = app.world().get::<Clipmap>(clipmap).unwrap();
+        let image = app
+            .world()
+            .resource::<Assets<Image>>()
+            .get(&clipmap.heightmap)
+            .unwrap();
+        image
+            .data
+            .as_deref()
+            .unwrap()
+            .chunks_exact(4)
+            .map(|texel| {
+                let h = read_texel(TextureFormat::R32Float, texel).unwrap();
+                h * (clipmap.max - clipmap.min) + clipmap.min
+            })
+            .collect()
+    }
+
+    fn assert_heights_eq(a: &[f32], b: &[f32]) {
+        for (a, b) in a.iter().zip(b) {
+            assert!((a - b).abs() < 1e-4, "{a} != {b}");
+        }
+    }
+
+    #[test]
+    fn undo_redo_stroke() {
+        let (mut app, clipmap) = app(usize::MAX);
+        let initial = heights(&app, clipmap);
+        brush(&mut app, clipmap, BrushMode::Raise, 1.0);
+        let raised = heights(&app, clipmap);
+        assert_ne!(initial, raised);
+
+        app.world_mut().write_message(ClipmapUndo { clipmap });
+        app.update();
+        assert_eq!(heights(&app, clipmap), initial);
+        let history = app.world().get::<ClipmapHistory>(clipmap).unwrap();
+        assert!(!history.can_undo() && history.can_redo());
+
+        app.world_mut().write_message(ClipmapRedo { clipmap });
+        app.update();
+        assert_eq!(heights(&app, clipmap), raised);
+    }
+
+    #[test]
+    fn undo_redo_renormalizing_stroke() {
+        let (mut app, clipmap) = app(usize::MAX);
+        let initial = heights(&app, clipmap);
+        // Raises the center above `Clipmap::max`.
+        brush(&mut app, clipmap, BrushMode::Raise, 8.0);
+        let raised = heights(&app, clipmap);
+        assert!(app.world().get::<Clipmap>(clipmap).unwrap().max > 10.0);
+
+        // Only the stroke is stored, not the whole renormalized heightmap.
+        let history = app.world().get::<ClipmapHistory>(clipmap).unwrap();
+        assert!(history.used < (SIZE * SIZE * 4) as usize);
+
+        app.world_mut().write_message(ClipmapUndo { clipmap });
+        app.update();
+        let restored = app.world().get::<Clipmap>(clipmap).unwrap();
+        assert_eq!((restored.min, restored.max), (0.0, 10.0));
+        assert_heights_eq(&heights(&app, clipmap), &initial);
+
+        app.world_mut().write_message(ClipmapRedo { clipmap });
+        app.update();
+        assert_heights_eq(&heights(&app, clipmap), &raised);
+    }
+
+    #[test]
+    fn new_stroke_clears_redo() {
+        let (mut app, clipmap) = app(usize::MAX);
+        brush(&mut app, clipmap, BrushMode::Raise, 1.0);
+        app.world_mut().write_message(ClipmapUndo { clipmap });
+        app.update();
+        brush(&mut app, clipmap, BrushMode::Lower, 1.0);
+        let history = app.world().get::<ClipmapHistory>(clipmap).unwrap();
+        assert!(history.can_undo() && !history.can_redo());
+    }
+
+    #[test]
+    fn budget_keeps_newest_stroke() {
+        let (mut app, clipmap) = app(1);
+        brush(&mut app, clipmap, BrushMode::Raise, 1.0);
+        brush(&mut app, clipmap, BrushMode::Lower, 0.5);
+        let lowered = heights(&app, clipmap);
+        let history = app.world().get::<ClipmapHistory>(clipmap).unwrap();
+        assert_eq!(history.undo.len(), 1);
+
+        app.world_mut().write_message(ClipmapUndo { clipmap });
+        app.update();
+        assert_ne!(heights(&app, clipmap), lowered);
+        let history = app.world().get::<ClipmapHistory>(clipmap).unwrap();
+        assert!(!history.can_undo());
+    }
+
+    #[test]
+    fn capture_mip_major_layer() {
+        // Two 4x4 layers with two mips, all first mips come before the second ones.
+        let mut image = Image::new_fill(
+            Extent3d {
+                width: 4,
+                height: 4,
+                depth_or_array_layers: 2,
+            },
+            TextureDimension::D2,
+            &[0],
+            TextureFormat::R8Unorm,
+            RenderAssetUsages::all(),
+        );
+        image.texture_descriptor.mip_level_count = 2;
+        image.data_order = TextureDataOrder::MipMajor;
+        image.data = Some((0..40).collect());
+
+        let texels = ClipmapHistory::capture(&image, 1, UVec2::new(1, 2), UVec2::new(3, 4));
+        assert_eq!(texels, [25, 26, 29, 30]);
+
+        image.data_order = TextureDataOrder::LayerMajor;
+        let texels = ClipmapHistory::capture(&image, 1, UVec2::new(1, 2), UVec2::new(3, 4));
+        assert_eq!(texels, [29, 30, 33, 34]);
+    }
+}
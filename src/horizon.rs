@@ -0,0 +1,612 @@
+use std::{
+    f64::consts::TAU,
+    io::{self, Write},
+    ops::Range,
+    thread,
+};
+
+use bevy::{math::DVec2, prelude::*};
+
+use crate::ktx2::{R16G16B16A16_SFLOAT, R32_SFLOAT, write_ktx2_header};
+
+/// Number of horizon directions sampled around every texel.
+pub const AZIMUTHS: usize = 360;
+
+/// Number of parallel lines traced at once for one azimuth.
+const STRIPE_LINES: i64 = 256;
+
+/// Texture layout of a horizon map.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Reflect)]
+pub enum HorizonEncoding {
+    /// One R32_SFLOAT layer per FFT coefficient.
+    #[default]
+    Fft,
+
+    /// Four FFT coefficients per RGBA16F layer, a quarter of the memory and texture fetches.
+    PackedFft,
+
+    /// One R32_SFLOAT layer per azimuth sector holding the tangent of the highest horizon
+    /// in the sector, interpolated between the sector centers.
+    /// Unlike FFT coefficients it doesn't ring at sharp ridges.
+    /// [`ClipmapHorizon::coeffs`] is the number of sectors.
+    Sectors,
+}
+
+impl HorizonEncoding {
+    /// Shader defs selecting the reconstruction of this encoding in `horizon.wgsl`.
+    pub(crate) fn shader_defs(self) -> &'static [&'static str] {
+        match self {
+            Self::Fft => &["CLIPMAP_HORIZON"],
+            Self::PackedFft => &["CLIPMAP_HORIZON", "CLIPMAP_HORIZON_PACKED"],
+            Self::Sectors => &["CLIPMAP_HORIZON", "CLIPMAP_HORIZON_SECTORS"],
+        }
+    }
+}
+
+/// Horizon map of a clipmap, shadowing directional, point and spot lights and occluding ambient light.
+#[derive(Clone, Debug)]
+pub struct ClipmapHorizon {
+    /// Horizon map texture.
+    pub texture: Handle<Image>,
+
+    /// Number of FFT coefficients, or of sectors with [`HorizonEncoding::Sectors`].
+    pub coeffs: u32,
+
+    /// Texture layout of `texture`.
+    pub encoding: HorizonEncoding,
+
+    /// Strength in `0..1` of the ambient occlusion derived from the horizon map.
+    /// It darkens the ambient and environment map light, zero disables it.
+    pub ao: f32,
+
+    /// Horizon shadows of all lights, a [`HorizonShadow`] on a directional light overrides them.
+    pub shadow: HorizonShadow,
+}
+
+impl Default for ClipmapHorizon {
+    fn default() -> Self {
+        Self {
+            texture: Handle::default(),
+            coeffs: 0,
+            encoding: HorizonEncoding::Fft,
+            ao: 0.0,
+            shadow: HorizonShadow::default(),
+        }
+    }
+}
+
+/// How lights are shadowed by the horizon map.
+/// Added to a [`DirectionalLight`] it overrides [`ClipmapHorizon::shadow`] for that light,
+/// the light is recognized by its direction.
+/// Directional lights pointing the same way share the settings of one of them, even those
+/// without a `HorizonShadow`.
+/// At most 10 lights have their own settings, the others use [`ClipmapHorizon::shadow`].
+#[derive(Component, Clone, Copy, Debug, PartialEq)]
+pub struct HorizonShadow {
+    /// Elevation in radians over which the light fades in above the horizon.
+    /// Point and spot lights fade in over the same range centered on the horizon.
+    pub softness: f32,
+
+    /// Elevation in radians added to the horizon, negative values let light through
+    /// just below the ridges.
+    pub bias: f32,
+
+    /// Strength in `0..1` of the shadow, zero disables it.
+    pub strength: f32,
+}
+
+impl Default for HorizonShadow {
+    fn default() -> Self {
+        Self {
+            softness: 0.3,
+            bias: 0.0,
+            strength: 1.0,
+        }
+    }
+}
+
+/// Horizon map, the CPU counterpart of [`ClipmapHorizon::texture`].
+pub struct HorizonMap {
+    /// Size in texels.
+    pub size: UVec2,
+
+    /// Layers row by row.
+    /// FFT coefficients: the real parts of the first `coeffs / 2 + 1` frequencies,
+    /// then the imaginary parts of the frequencies starting from 1.
+    /// Sectors: the horizon tangent of every sector.
+    pub layers: Vec<Vec<f32>>,
+}
+
+/// How the horizon tangents of one azimuth are added to the layers.
+enum Accumulate {
+    /// Adds the tangent times the factor of every layer.
+    Sum(Vec<f32>),
+
+    /// Keeps the highest tangent in the layer.
+    Max(usize),
+}
+
+impl HorizonMap {
+    /// Bakes the FFT-compressed horizon map of a heightmap given as normalized heights row by row.
+    /// Horizon tangents are measured in normalized height per texel, the same as `convert/clipmap.py`.
+    ///
+    /// Every azimuth is traced in stripes of parallel lines that are accumulated into the
+    /// coefficients right away, so memory use grows with the coefficient count only.
+    pub fn bake(heights: &[f32], size: UVec2, coeffs: u32) -> Self {
+        let frequencies = coeffs as usize / 2;
+        let layers = coeffs as usize + 1;
+        Self::trace(heights, size, layers, |azimuth| {
+            // numpy.fft.rfft: X_k = sum x_n * exp(-2 pi i k n / N)
+            Accumulate::Sum(
+                (0..layers)
+                    .map(|layer| {
+                        let (k, imaginary) = if layer <= frequencies {
+                            (layer, false)
+                        } else if layer <= 2 * frequencies {
+                            (layer - frequencies, true)
+                        } else {
+                            return 0.0;
+                        };
+                        let (sin, cos) = (TAU * (k * azimuth) as f64 / AZIMUTHS as f64).sin_cos();
+                        (if imaginary { -sin } else { cos }) as f32
+                    })
+                    .collect(),
+            )
+        })
+    }
+
+    /// Bakes the sector horizon map of a heightmap, see [`HorizonEncoding::Sectors`].
+    /// Sector `i` covers the azimuths from `i * 360 / sectors` degrees.
+    pub fn bake_sectors(heights: &[f32], size: UVec2, sectors: u32) -> Self {
+        let sectors = sectors.max(1) as usize;
+        Self::trace(heights, size, sectors, |azimuth| {
+            Accumulate::Max(azimuth * sectors / AZIMUTHS)
+        })
+    }
+
+    fn trace(
+        heights: &[f32],
+        size: UVec2,
+        layer_count: usize,
+        accumulate: impl Fn(usize) -> Accumulate,
+    ) -> Self {
+        let (width, height) = (size.x as usize, size.y as usize);
+        let mut layers = vec![vec![0.0f32; width * height]; layer_count];
+
+        for azimuth in 0..AZIMUTHS {
+            let accumulate = accumulate(azimuth);
+            let lines = AzimuthLines::new(width, height, azimuth as f64);
+            let (first, last) = lines.range();
+            for start in (first..=last).step_by(STRIPE_LINES as usize) {
+                let stripe = start..(start + STRIPE_LINES).min(last + 1);
+                let traced = par_map(stripe.clone().count(), |i| {
+                    lines.trace(heights, stripe.start + i as i64)
+                });
+                par_layers(&mut layers, width, |y, rows| {
+                    for x in lines.row_span(y, &stripe) {
+                        let (line, sample) = lines.nearest(x, y);
+                        if !stripe.contains(&line) {
+                            continue;
+                        }
+                        let (first_sample, tangents) = &traced[(line - stripe.start) as usize];
+                        let Some(&tangent) = usize::try_from(sample - first_sample)
+                            .ok()
+                            .and_then(|i| tangents.get(i))
+                        else {
+                            continue;
+                        };
+                        match &accumulate {
+                            Accumulate::Sum(factors) => {
+                                for (row, factor) in rows.iter_mut().zip(factors) {
+                                    row[x] += tangent * factor;
+                                }
+                            }
+                            Accumulate::Max(layer) => {
+                                rows[*layer][x] = rows[*layer][x].max(tangent);
+                            }
+                        }
+                    }
+                });
+            }
+        }
+
+        Self { size, layers }
+    }
+
+    /// Writes the map as a KTX2 array with the encoding, the same layout `convert/clipmap.py horizon` produces.
+    /// Maps made with [`HorizonMap::bake_sectors`] must be written as [`HorizonEncoding::Sectors`].
+    pub fn write_ktx2(&self, writer: &mut impl Write, encoding: HorizonEncoding) -> io::Result<()> {
+        let texels = self.size.element_product() as usize;
+        match encoding {
+            HorizonEncoding::Fft | HorizonEncoding::Sectors => {
+                write_ktx2_header(
+                    writer,
+                    &R32_SFLOAT,
+                    self.size,
+                    Some(self.layers.len() as u32),
+                    self.layers.len() * texels * 4,
+                )?;
+                for layer in &self.layers {
+                    for chunk in layer.chunks(4096) {
+                        let bytes = chunk
+                            .iter()
+                            .flat_map(|v| v.to_le_bytes())
+                            .collect::<Vec<_>>();
+                        writer.write_all(&bytes)?;
+                    }
+                }
+            }
+            HorizonEncoding::PackedFft => {
+                let layers = self.layers.chunks(4).collect::<Vec<_>>();
+                write_ktx2_header(
+                    writer,
+                    &R16G16B16A16_SFLOAT,
+                    self.size,
+                    Some(layers.len() as u32),
+                    layers.len() * texels * 8,
+                )?;
+                for channels in layers {
+                    for start in (0..texels).step_by(4096) {
+                        let bytes = (start..(start + 4096).min(texels))
+                            .flat_map(|texel| {
+                                (0..4)
+                                    .map(move |c| channels.get(c).map_or(0.0, |layer| layer[texel]))
+                            })
+                            .flat_map(|v| f16_bits(v).to_le_bytes())
+                            .collect::<Vec<_>>();
+                        writer.write_all(&bytes)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Half float bits of the value, rounded to nearest.
+fn f16_bits(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32 - 127 + 15;
+    let mantissa = bits & 0x7f_ffff;
+    if exponent >= 31 {
+        // Infinity, NaN or too large.
+        let nan = (bits & 0x7fff_ffff) > 0x7f80_0000;
+        return sign | 0x7c00 | if nan { 0x200 } else { 0 };
+    }
+    if exponent <= 0 {
+        // Subnormal or zero.
+        if exponent < -10 {
+            return sign;
+        }
+        let mantissa = mantissa | 0x80_0000;
+        let shift = (14 - exponent) as u32;
+        let round = (mantissa >> (shift - 1)) & 1;
+        return sign | ((mantissa >> shift) + round) as u16;
+    }
+    // Rounding may carry into the exponent, up to infinity.
+    let half = ((exponent as u32) << 10) | (mantissa >> 13);
+    sign | (half + ((mantissa >> 12) & 1)) as u16
+}
+
+/// Parallel lines through the heightmap toward one azimuth, the rows of the heightmap rotated
+/// around its center like `cv2.getRotationMatrix2D` does in `convert/clipmap.py`.
+/// Line `j` passes `j` texels from the center, its samples are one texel apart.
+struct AzimuthLines {
+    width: usize,
+    height: usize,
+    center: DVec2,
+    along: DVec2,
+    across: DVec2,
+}
+
+impl AzimuthLines {
+    fn new(width: usize, height: usize, degrees: f64) -> Self {
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        Self {
+            width,
+            height,
+            center: DVec2::new(width as f64, height as f64) / 2.0,
+            along: DVec2::new(cos, sin),
+            across: DVec2::new(-sin, cos),
+        }
+    }
+
+    /// First and last line nearest to a texel.
+    fn range(&self) -> (i64, i64) {
+        let max = DVec2::new(self.width as f64 - 1.0, self.height as f64 - 1.0);
+        let corners = [
+            DVec2::ZERO,
+            DVec2::new(max.x, 0.0),
+            DVec2::new(0.0, max.y),
+            max,
+        ]
+        .map(|corner| (corner - self.center).dot(self.across));
+        let min = corners.into_iter().fold(f64::MAX, f64::min);
+        let max = corners.into_iter().fold(f64::MIN, f64::max);
+        (min.round() as i64, max.round() as i64)
+    }
+
+    /// Nearest line and sample of the texel.
+    fn nearest(&self, x: usize, y: usize) -> (i64, i64) {
+        let p = DVec2::new(x as f64, y as f64) - self.center;
+        (
+            p.dot(self.across).round() as i64,
+            p.dot(self.along).round() as i64,
+        )
+    }
+
+    /// Texels of the row that may be nearest to one of the lines.
+    fn row_span(&self, y: usize, lines: &Range<i64>) -> Range<usize> {
+        let offset = (y as f64 - self.center.y) * self.across.y;
+        let (lo, hi) = (lines.start as f64 - 0.5, lines.end as f64 - 0.5);
+        if self.across.x.abs() < 1e-9 {
+            return if (lo..hi).contains(&offset) {
+                0..self.width
+            } else {
+                0..0
+            };
+        }
+        let a = (lo - offset) / self.across.x + self.center.x;
+        let b = (hi - offset) / self.across.x + self.center.x;
+        let max = (a.max(b).ceil() + 1.0).clamp(0.0, self.width as f64) as usize;
+        let min = ((a.min(b).floor() - 1.0).max(0.0) as usize).min(max);
+        min..max
+    }
+
+    /// Index of the first sample of the line and the horizon tangents of its samples.
+    /// The line is traced up to one texel past the heightmap, so every texel has a sample nearby.
+    fn trace(&self, heights: &[f32], line: i64) -> (i64, Vec<f32>) {
+        let origin = self.center + self.across * line as f64;
+        let (mut t_min, mut t_max) = (f64::MIN, f64::MAX);
+        for (origin, along, size) in [
+            (origin.x, self.along.x, self.width as f64),
+            (origin.y, self.along.y, self.height as f64),
+        ] {
+            if along.abs() < 1e-9 {
+                if origin < -1.0 || origin > size {
+                    return (0, vec![]);
+                }
+                continue;
+            }
+            let (a, b) = ((-1.0 - origin) / along, (size - origin) / along);
+            t_min = t_min.max(a.min(b));
+            t_max = t_max.min(a.max(b));
+        }
+        if t_min > t_max {
+            return (0, vec![]);
+        }
+
+        let (first, last) = (t_min.floor() as i64, t_max.ceil() as i64);
+        let row = (first..=last)
+            .map(|t| self.bilinear(heights, origin + self.along * t as f64))
+            .collect::<Vec<_>>();
+        let mut tangents = vec![0.0; row.len()];
+        row_tangents(&row, &mut tangents);
+        (first, tangents)
+    }
+
+    /// Bilinear height replicating the border, like `cv2.BORDER_REPLICATE`.
+    fn bilinear(&self, heights: &[f32], p: DVec2) -> f32 {
+        let texel = |x: i64, y: i64| {
+            let x = x.clamp(0, self.width as i64 - 1) as usize;
+            let y = y.clamp(0, self.height as i64 - 1) as usize;
+            heights[y * self.width + x]
+        };
+        let p0 = p.floor();
+        let (fx, fy) = ((p.x - p0.x) as f32, (p.y - p0.y) as f32);
+        let (x0, y0) = (p0.x as i64, p0.y as i64);
+        let top = texel(x0, y0) * (1.0 - fx) + texel(x0 + 1, y0) * fx;
+        let bottom = texel(x0, y0 + 1) * (1.0 - fx) + texel(x0 + 1, y0 + 1) * fx;
+        top * (1.0 - fy) + bottom * fy
+    }
+}
+
+/// Tangent of the highest point to the right of every texel of the row.
+/// Walks the convex hull of the points to the right, starting from the neighbour.
+fn row_tangents(row: &[f32], tangents: &mut [f32]) {
+    let width = row.len();
+    let mut horizons = (0..width).collect::<Vec<_>>();
+    for j in (0..width.saturating_sub(1)).rev() {
+        let mut k = j + 1;
+        loop {
+            let nk = horizons[k];
+            let slope_k = ((row[k] - row[j]) / (k - j) as f32).max(0.0);
+            let slope_nk = if nk != k {
+                ((row[nk] - row[j]) / (nk - j) as f32).max(0.0)
+            } else {
+                0.0
+            };
+            if slope_k > slope_nk {
+                horizons[j] = k;
+                break;
+            }
+            if nk == k {
+                break;
+            }
+            k = nk;
+        }
+    }
+    for (j, tangent) in tangents.iter_mut().enumerate() {
+        let k = horizons[j];
+        *tangent = if k == j {
+            0.0
+        } else {
+            (row[k] - row[j]) / (k - j) as f32
+        };
+    }
+}
+
+fn threads() -> usize {
+    thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+/// Maps `0..count` on all cores.
+fn par_map<T: Send>(count: usize, f: impl Fn(usize) -> T + Sync) -> Vec<T> {
+    let per_thread = count.div_ceil(threads()).max(1);
+    thread::scope(|scope| {
+        let handles = (0..count)
+            .step_by(per_thread)
+            .map(|start| {
+                let f = &f;
+                scope.spawn(move || {
+                    (start..(start + per_thread).min(count))
+                        .map(f)
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap())
+            .collect()
+    })
+}
+
+/// Runs `f` for every row of the layers on all cores, with the row of every layer.
+fn par_layers(layers: &mut [Vec<f32>], width: usize, f: impl Fn(usize, &mut [&mut [f32]]) + Sync) {
+    let Some(rows) = layers.first().map(|layer| layer.len() / width.max(1)) else {
+        return;
+    };
+    let rows_per_thread = rows.div_ceil(threads()).max(1);
+    let mut chunks = (0..rows.div_ceil(rows_per_thread))
+        .map(|_| vec![])
+        .collect::<Vec<Vec<&mut [f32]>>>();
+    for layer in layers {
+        for (chunk, part) in chunks
+            .iter_mut()
+            .zip(layer.chunks_mut(rows_per_thread * width))
+        {
+            chunk.push(part);
+        }
+    }
+    thread::scope(|scope| {
+        for (chunk_index, mut chunk) in chunks.into_iter().enumerate() {
+            let f = &f;
+            scope.spawn(move || {
+                for i in 0..chunk[0].len() / width {
+                    let mut rows = chunk
+                        .iter_mut()
+                        .map(|part| &mut part[i * width..(i + 1) * width])
+                        .collect::<Vec<_>>();
+                    f(chunk_index * rows_per_thread + i, &mut rows);
+                }
+            });
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Wide enough for the lines of some azimuths to span two stripes.
+    const SIZE: UVec2 = UVec2::new(270, 3);
+
+    fn heights() -> Vec<f32> {
+        (0..SIZE.element_product())
+            .map(|i| {
+                let p = Vec2::new((i % SIZE.x) as f32, (i / SIZE.x) as f32);
+                (p.dot(Vec2::new(12.9898, 78.233)).sin() * 43758.547)
+                    .fract()
+                    .abs()
+            })
+            .collect()
+    }
+
+    /// Horizon tangents of every texel for one azimuth, the highest slope along the nearest line
+    /// found by testing every sample past the texel.
+    fn reference_tangents(heights: &[f32], azimuth: usize) -> Vec<Option<f64>> {
+        let (width, height) = (SIZE.x as usize, SIZE.y as usize);
+        let lines = AzimuthLines::new(width, height, azimuth as f64);
+        (0..width * height)
+            .map(|i| {
+                let (line, sample) = lines.nearest(i % width, i / width);
+                let origin = lines.center + lines.across * line as f64;
+                // Samples reach one texel past the heightmap, rounded outward.
+                let (mut t_min, mut t_max) = (f64::MIN, f64::MAX);
+                for (origin, along, size) in [
+                    (origin.x, lines.along.x, width as f64),
+                    (origin.y, lines.along.y, height as f64),
+                ] {
+                    if along.abs() >= 1e-9 {
+                        let (a, b) = ((-1.0 - origin) / along, (size - origin) / along);
+                        t_min = t_min.max(a.min(b));
+                        t_max = t_max.min(a.max(b));
+                    }
+                }
+                let (first, last) = (t_min.floor() as i64, t_max.ceil() as i64);
+                if !(first..=last).contains(&sample) {
+                    return None;
+                }
+                let h = |t: i64| lines.bilinear(heights, origin + lines.along * t as f64) as f64;
+                let tangent = (sample + 1..=last)
+                    .map(|t| (h(t) - h(sample)) / (t - sample) as f64)
+                    .fold(0.0, f64::max);
+                Some(tangent)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn streamed_bake_matches_reference() {
+        let heights = heights();
+        let coeffs = 4;
+        let fft = HorizonMap::bake(&heights, SIZE, coeffs);
+        let sectors = HorizonMap::bake_sectors(&heights, SIZE, coeffs);
+        assert_eq!(fft.layers.len(), coeffs as usize + 1);
+
+        let texels = SIZE.element_product() as usize;
+        let mut real = vec![[0.0f64; 3]; texels];
+        let mut imaginary = vec![[0.0f64; 3]; texels];
+        let mut highest = vec![[0.0f64; 4]; texels];
+        for azimuth in 0..AZIMUTHS {
+            for (i, tangent) in reference_tangents(&heights, azimuth)
+                .into_iter()
+                .enumerate()
+            {
+                let tangent = tangent.unwrap_or_default();
+                for k in 0..3 {
+                    let angle = TAU * (k * azimuth) as f64 / AZIMUTHS as f64;
+                    real[i][k] += tangent * angle.cos();
+                    imaginary[i][k] -= tangent * angle.sin();
+                }
+                let sector = &mut highest[i][azimuth * 4 / AZIMUTHS];
+                *sector = sector.max(tangent);
+            }
+        }
+
+        for i in 0..texels {
+            let expected = [
+                real[i][0],
+                real[i][1],
+                real[i][2],
+                imaginary[i][1],
+                imaginary[i][2],
+            ];
+            for (layer, expected) in fft.layers.iter().zip(expected) {
+                assert!(
+                    (layer[i] as f64 - expected).abs() < 1e-3,
+                    "texel {i}: {} != {expected}",
+                    layer[i]
+                );
+            }
+            for (layer, expected) in sectors.layers.iter().zip(highest[i]) {
+                assert!((layer[i] as f64 - expected).abs() < 1e-5);
+            }
+        }
+    }
+
+    #[test]
+    fn row_tangents_match_brute_force() {
+        let row = [0.3, 0.1, 0.5, 0.2, 0.2, 0.9, 0.0, 0.4, 0.4, 0.1];
+        let mut tangents = [0.0; 10];
+        row_tangents(&row, &mut tangents);
+        for (j, tangent) in tangents.into_iter().enumerate() {
+            let expected = (j + 1..row.len())
+                .map(|k| (row[k] - row[j]) / (k - j) as f32)
+                .fold(0.0, f32::max);
+            assert!((tangent - expected).abs() < 1e-6);
+        }
+    }
+}
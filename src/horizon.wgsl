@@ -0,0 +1,136 @@
+#define_import_path bevy_clipmap::horizon
+
+// Horizon map reconstruction shared by the terrain and `HorizonShadowMaterial`.
+// Tangents are stored in normalized height per texel, `height_range` scales them to world heights.
+// Only the first mip is sampled, so the functions can be called in non-uniform control flow.
+
+#import bevy_render::maths::{HALF_PI, PI_2}
+
+#ifdef CLIPMAP_HORIZON
+@group(#{MATERIAL_BIND_GROUP}) @binding(104) var horizon_texture: texture_2d_array<f32>;
+@group(#{MATERIAL_BIND_GROUP}) @binding(105) var horizon_sampler: sampler;
+@group(#{MATERIAL_BIND_GROUP}) @binding(106) var<uniform> horizon_coeffs: u32;
+@group(#{MATERIAL_BIND_GROUP}) @binding(127) var<uniform> horizon_ao: f32;
+
+const MAX_LIGHTS: u32 = 10u;
+
+struct HorizonShadow {
+    softness: f32,
+    bias: f32,
+    strength: f32,
+}
+
+// Directional light with its own `HorizonShadow`.
+struct HorizonLight {
+    direction: vec3<f32>,
+    softness: f32,
+    bias: f32,
+    strength: f32,
+}
+
+struct HorizonLights {
+    lights: array<HorizonLight, MAX_LIGHTS>,
+    count: u32,
+}
+
+@group(#{MATERIAL_BIND_GROUP}) @binding(128) var<uniform> horizon_shadow_settings: HorizonShadow;
+@group(#{MATERIAL_BIND_GROUP}) @binding(129) var<uniform> horizon_lights: HorizonLights;
+
+fn reconstruct_horizon(uv: vec2<f32>, theta: f32, height_range: f32) -> f32 {
+    const N = 360.0;
+
+#ifdef CLIPMAP_HORIZON_PACKED
+    // Four coefficients per layer, in the same order as the unpacked layers.
+    let k = horizon_coeffs / 2;
+    var horizon = 0.0;
+    for (var layer = 0u; layer * 4 <= horizon_coeffs; layer++) {
+        let coeffs = textureSampleLevel(horizon_texture, horizon_sampler, uv, layer, 0.0);
+        for (var c = 0u; c < 4; c++) {
+            let i = layer * 4 + c;
+            if i == 0 {
+                horizon += coeffs[c] / N;
+            } else if i <= k {
+                horizon += (2.0 / N) * coeffs[c] * cos(f32(i) * theta);
+            } else if i <= 2 * k {
+                horizon -= (2.0 / N) * coeffs[c] * sin(f32(i - k) * theta);
+            }
+        }
+    }
+#else
+    var horizon = textureSampleLevel(horizon_texture, horizon_sampler, uv, 0, 0.0).r / N;
+    for (var i = 1u; i <= horizon_coeffs / 2; i++) {
+        let angle = f32(i) * theta;
+        let a = textureSampleLevel(horizon_texture, horizon_sampler, uv, i, 0.0).r;
+        let b = textureSampleLevel(horizon_texture, horizon_sampler, uv, i + horizon_coeffs / 2, 0.0).r;
+        horizon += (2.0 / N) * (a * cos(angle) - b * sin(angle));
+    }
+#endif
+    horizon *= height_range;
+    return clamp(atan(horizon), 0.0, HALF_PI);
+}
+
+// Tangent of the highest horizon per azimuth sector, interpolated between the sector centers.
+fn reconstruct_horizon_sectors(uv: vec2<f32>, theta: f32, height_range: f32) -> f32 {
+    let sectors = f32(horizon_coeffs);
+    let x = fract(theta / PI_2) * sectors - 0.5;
+    let i = floor(x);
+    let a = textureSampleLevel(horizon_texture, horizon_sampler, uv, u32((i + sectors) % sectors), 0.0).r;
+    let b = textureSampleLevel(horizon_texture, horizon_sampler, uv, u32((i + 1.0) % sectors), 0.0).r;
+    let horizon = mix(a, b, x - i) * height_range;
+    return clamp(atan(horizon), 0.0, HALF_PI);
+}
+
+// Highest horizon elevation toward the azimuth.
+fn horizon_elevation(uv: vec2<f32>, theta: f32, height_range: f32) -> f32 {
+#ifdef CLIPMAP_HORIZON_SECTORS
+    return reconstruct_horizon_sectors(uv, theta, height_range);
+#else
+    return reconstruct_horizon(uv, theta, height_range);
+#endif
+}
+
+// Cosine-weighted sky visibility above the horizon, 1 - mean(sin^2(elevation)).
+fn horizon_visibility(uv: vec2<f32>, height_range: f32) -> f32 {
+    const DIRECTIONS = 8u;
+    var occlusion = 0.0;
+    for (var i = 0u; i < DIRECTIONS; i++) {
+        let elevation = sin(horizon_elevation(uv, PI_2 * f32(i) / f32(DIRECTIONS), height_range));
+        occlusion += elevation * elevation;
+    }
+    return 1.0 - occlusion / f32(DIRECTIONS);
+}
+
+// Shadow settings of the directional light, the ones of the clipmap unless the light has its own.
+fn directional_settings(direction_to_light: vec3<f32>) -> HorizonShadow {
+    for (var i = 0u; i < horizon_lights.count; i++) {
+        let light = horizon_lights.lights[i];
+        if dot(light.direction, direction_to_light) > 0.9999 {
+            return HorizonShadow(light.softness, light.bias, light.strength);
+        }
+    }
+    return horizon_shadow_settings;
+}
+
+// Visibility of a directional light above the horizon, fading in over the softness.
+fn horizon_shadow(uv: vec2<f32>, height_range: f32, direction_to_light: vec3<f32>) -> f32 {
+    let settings = directional_settings(direction_to_light);
+    let theta = atan2(direction_to_light.z, direction_to_light.x);
+    let elevation = horizon_elevation(uv, theta, height_range) + settings.bias;
+    let softness = max(settings.softness, 1e-4);
+    let visibility = smoothstep(elevation, elevation + softness, asin(direction_to_light.y));
+    return mix(1.0, visibility, saturate(settings.strength));
+}
+
+// Visibility of a point or spot light at the offset `to_light` from the fragment.
+// The horizon is clamped to zero, so the penumbra is centered on it to keep lights just above
+// flat terrain lit. Terrain behind the light occludes it as well.
+fn horizon_local_shadow(uv: vec2<f32>, height_range: f32, to_light: vec3<f32>) -> f32 {
+    let settings = horizon_shadow_settings;
+    let theta = atan2(to_light.z, to_light.x);
+    let elevation = horizon_elevation(uv, theta, height_range) + settings.bias;
+    let softness = max(settings.softness, 1e-4);
+    let light_elevation = atan2(to_light.y, length(to_light.xz));
+    let visibility = smoothstep(elevation - 0.5 * softness, elevation + 0.5 * softness, light_elevation);
+    return mix(1.0, visibility, saturate(settings.strength));
+}
+#endif
@@ -0,0 +1,471 @@
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+};
+
+use bevy::{
+    asset::{AssetPath, RenderAssetUsages, embedded_asset, embedded_path},
+    prelude::*,
+    render::{
+        ExtractSchedule, MainWorld, Render, RenderApp, RenderStartup, RenderSystems,
+        render_asset::RenderAssets,
+        render_graph::{self, RenderGraph, RenderLabel},
+        render_resource::{
+            BindGroup, BindGroupEntries, BindGroupLayoutDescriptor, BindGroupLayoutEntries,
+            CachedComputePipelineId, ComputePassDescriptor, ComputePipelineDescriptor, Extent3d,
+            FilterMode, Origin3d, PipelineCache, Sampler, SamplerBindingType, SamplerDescriptor,
+            ShaderStages, ShaderType, StorageTextureAccess, TexelCopyTextureInfo, Texture,
+            TextureAspect, TextureDescriptor, TextureDimension, TextureFormat, TextureSampleType,
+            TextureUsages, TextureView, TextureViewDescriptor, TextureViewDimension, UniformBuffer,
+            binding_types::{sampler, texture_2d, texture_storage_2d_array, uniform_buffer},
+        },
+        renderer::{RenderContext, RenderDevice, RenderQueue},
+        texture::GpuImage,
+    },
+};
+
+use crate::{
+    Clipmap,
+    horizon::{AZIMUTHS, HorizonEncoding},
+    levels::ClipmapLevels,
+};
+
+/// Most azimuths traced by one dispatch, `MAX_BATCH` in `horizon_bake.wgsl`.
+const MAX_BATCH: u32 = 32;
+
+/// Bakes the texture of `Clipmap::horizon` on the GPU from `Clipmap::heightmap` with its
+/// coefficient or sector count, replacing the texture set on the clipmap.
+/// Clipmaps without a horizon are not baked.
+/// Regions changed by [`ClipmapBrush`](crate::ClipmapBrush) and undo/redo are baked again,
+/// modifying the heightmap asset bakes the whole map again.
+/// Maps with [`HorizonEncoding::PackedFft`] are baked as [`HorizonEncoding::Fft`].
+/// The azimuths are summed in a scratch texture the size of the baked region, which is copied
+/// into the horizon map once all of them are baked, so the terrain never shows a partial bake.
+/// Clipmaps with a [`ClipmapHeightSource`](crate::ClipmapHeightSource) are not baked.
+#[derive(Component)]
+pub struct ClipmapHorizonBake {
+    /// Size of the horizon map in texels.
+    pub size: UVec2,
+
+    /// How far the horizon is searched in horizon map texels.
+    /// Edits are baked again up to this distance around the changed region.
+    pub max_distance: u32,
+
+    /// Number of azimuths baked per frame, at most 32.
+    /// A bake is spread over `360 / azimuths_per_frame` frames.
+    pub azimuths_per_frame: u32,
+
+    /// Changed region of the heightmap in heightmap texels.
+    dirty: Option<[UVec2; 2]>,
+}
+
+impl ClipmapHorizonBake {
+    pub fn new(size: UVec2) -> Self {
+        Self {
+            size,
+            max_distance: 256,
+            azimuths_per_frame: 8,
+            dirty: Some([UVec2::ZERO, UVec2::MAX]),
+        }
+    }
+
+    /// Marks the region `min..max` of the heightmap in heightmap texels to be baked again.
+    pub fn mark_dirty(&mut self, min: UVec2, max: UVec2) {
+        self.dirty = Some(union(self.dirty, [min, max]));
+    }
+}
+
+/// Texture the baked horizon map is copied into.
+pub(crate) fn horizon_image(size: UVec2, coeffs: u32, encoding: HorizonEncoding) -> Image {
+    let mut image = Image::new_uninit(
+        Extent3d {
+            width: size.x,
+            height: size.y,
+            depth_or_array_layers: layer_count(coeffs, encoding),
+        },
+        TextureDimension::D2,
+        TextureFormat::R32Float,
+        RenderAssetUsages::RENDER_WORLD,
+    );
+    // A single layer is still sampled as an array.
+    image.texture_view_descriptor = Some(TextureViewDescriptor {
+        dimension: Some(TextureViewDimension::D2Array),
+        ..default()
+    });
+    image
+}
+
+fn layer_count(coeffs: u32, encoding: HorizonEncoding) -> u32 {
+    match encoding {
+        HorizonEncoding::Sectors => coeffs.max(1),
+        _ => coeffs + 1,
+    }
+}
+
+fn union(region: Option<[UVec2; 2]>, [min, max]: [UVec2; 2]) -> [UVec2; 2] {
+    match region {
+        Some([region_min, region_max]) => [region_min.min(min), region_max.max(max)],
+        None => [min, max],
+    }
+}
+
+pub(crate) struct HorizonBakePlugin;
+
+impl Plugin for HorizonBakePlugin {
+    fn build(&self, app: &mut App) {
+        embedded_asset!(app, "horizon_bake.wgsl");
+
+        app.init_resource::<HorizonBakeRequests>();
+
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+        render_app
+            .init_resource::<HorizonBakes>()
+            .add_systems(RenderStartup, init_horizon_bake_pipeline)
+            .add_systems(ExtractSchedule, extract_horizon_bakes)
+            .add_systems(
+                Render,
+                prepare_horizon_bakes.in_set(RenderSystems::PrepareBindGroups),
+            );
+
+        let mut render_graph = render_app.world_mut().resource_mut::<RenderGraph>();
+        render_graph.add_node(HorizonBakeLabel, HorizonBakeNode);
+        render_graph.add_node_edge(HorizonBakeLabel, bevy::render::graph::CameraDriverLabel);
+    }
+}
+
+/// A region of a horizon map to bake.
+struct HorizonBakeRequest {
+    horizon: AssetId<Image>,
+    heightmap: AssetId<Image>,
+    coeffs: u32,
+    encoding: HorizonEncoding,
+    max_distance: u32,
+    azimuths_per_frame: u32,
+    /// Region in horizon map texels.
+    region: [UVec2; 2],
+}
+
+/// Horizon map regions waiting to be baked.
+#[derive(Resource, Default)]
+pub(crate) struct HorizonBakeRequests(Vec<HorizonBakeRequest>);
+
+/// Turns the changed heightmap regions into bake requests.
+pub(crate) fn queue_horizon_bakes(
+    mut events: MessageReader<AssetEvent<Image>>,
+    images: Res<Assets<Image>>,
+    mut requests: ResMut<HorizonBakeRequests>,
+    mut clipmaps: Query<(&Clipmap, &mut ClipmapHorizonBake), Without<ClipmapLevels>>,
+) {
+    let modified = events
+        .read()
+        .filter_map(|event| match event {
+            AssetEvent::Modified { id } => Some(*id),
+            _ => None,
+        })
+        .collect::<HashSet<_>>();
+
+    for (clipmap, mut bake) in &mut clipmaps {
+        if modified.contains(&clipmap.heightmap.id()) {
+            bake.mark_dirty(UVec2::ZERO, UVec2::MAX);
+        }
+        let (Some(horizon), Some([min, max])) = (&clipmap.horizon, bake.dirty) else {
+            continue;
+        };
+        let Some(heightmap) = images.get(&clipmap.heightmap) else {
+            continue;
+        };
+        bake.dirty = None;
+
+        // Texels see the change up to the search distance away.
+        let size = heightmap.size();
+        let scale = bake.size.as_vec2() / size.as_vec2();
+        let reach = UVec2::splat(bake.max_distance + 1);
+        let min = (min.min(size).as_vec2() * scale).floor().as_uvec2();
+        let max = (max.min(size).as_vec2() * scale).ceil().as_uvec2();
+        requests.0.push(HorizonBakeRequest {
+            horizon: horizon.texture.id(),
+            heightmap: clipmap.heightmap.id(),
+            coeffs: horizon.coeffs,
+            encoding: horizon.encoding,
+            max_distance: bake.max_distance,
+            azimuths_per_frame: bake.azimuths_per_frame.clamp(1, MAX_BATCH),
+            region: [min.saturating_sub(reach), (max + reach).min(bake.size)],
+        });
+    }
+}
+
+#[derive(ShaderType, Clone, Copy)]
+struct HorizonBakeParams {
+    origin: UVec2,
+    size: UVec2,
+    map_size: UVec2,
+    azimuth_start: u32,
+    azimuth_count: u32,
+    coeffs: u32,
+    layer_count: u32,
+    sectors: u32,
+    max_distance: f32,
+}
+
+/// Progress of the bake of one horizon map.
+struct HorizonBakeState {
+    request: HorizonBakeRequest,
+    /// Next azimuth to bake over `request.region`.
+    azimuth: u32,
+    /// Region requested while baking, baked once the current bake is done.
+    pending: Option<[UVec2; 2]>,
+    /// Sums of the azimuths baked so far over `request.region`, created with the first batch.
+    scratch: Option<(Texture, TextureView)>,
+}
+
+struct HorizonBakePass {
+    bind_group: BindGroup,
+    workgroups: UVec2,
+    /// Scratch texture, horizon map and region origin, set for the last batch of a bake.
+    copy: Option<(Texture, Texture, UVec2)>,
+    /// Size of the region and number of layers.
+    extent: Extent3d,
+}
+
+/// Horizon map bakes in progress and the dispatches of this frame.
+#[derive(Resource, Default)]
+struct HorizonBakes {
+    states: HashMap<AssetId<Image>, HorizonBakeState>,
+    passes: Vec<HorizonBakePass>,
+}
+
+#[derive(Resource)]
+struct HorizonBakePipeline {
+    layout: BindGroupLayoutDescriptor,
+    sampler: Sampler,
+    pipeline: CachedComputePipelineId,
+}
+
+fn init_horizon_bake_pipeline(
+    mut commands: Commands,
+    render_device: Res<RenderDevice>,
+    asset_server: Res<AssetServer>,
+    pipeline_cache: Res<PipelineCache>,
+) {
+    let layout = BindGroupLayoutDescriptor::new(
+        "horizon_bake_layout",
+        &BindGroupLayoutEntries::sequential(
+            ShaderStages::COMPUTE,
+            (
+                texture_2d(TextureSampleType::Float { filterable: true }),
+                sampler(SamplerBindingType::Filtering),
+                texture_storage_2d_array(TextureFormat::R32Float, StorageTextureAccess::ReadWrite),
+                uniform_buffer::<HorizonBakeParams>(false),
+            ),
+        ),
+    );
+    let sampler = render_device.create_sampler(&SamplerDescriptor {
+        mag_filter: FilterMode::Linear,
+        min_filter: FilterMode::Linear,
+        ..default()
+    });
+    let pipeline = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+        label: Some("horizon_bake_pipeline".into()),
+        layout: vec![layout.clone()],
+        shader: asset_server.load(
+            AssetPath::from_path_buf(embedded_path!("horizon_bake.wgsl")).with_source("embedded"),
+        ),
+        entry_point: Some(Cow::from("bake")),
+        ..default()
+    });
+    commands.insert_resource(HorizonBakePipeline {
+        layout,
+        sampler,
+        pipeline,
+    });
+}
+
+fn extract_horizon_bakes(mut main_world: ResMut<MainWorld>, mut bakes: ResMut<HorizonBakes>) {
+    let requests = std::mem::take(&mut main_world.resource_mut::<HorizonBakeRequests>().0);
+    for request in requests {
+        match bakes.states.get_mut(&request.horizon) {
+            Some(state) => state.pending = Some(union(state.pending, request.region)),
+            None => {
+                bakes.states.insert(
+                    request.horizon,
+                    HorizonBakeState {
+                        request,
+                        azimuth: 0,
+                        pending: None,
+                        scratch: None,
+                    },
+                );
+            }
+        }
+    }
+}
+
+/// Prepares the next batch of azimuths of every bake in progress.
+fn prepare_horizon_bakes(
+    mut bakes: ResMut<HorizonBakes>,
+    pipeline: Res<HorizonBakePipeline>,
+    pipeline_cache: Res<PipelineCache>,
+    gpu_images: Res<RenderAssets<GpuImage>>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    let bakes = &mut *bakes;
+    bakes.passes.clear();
+    // Bakes wait for the pipeline, so no azimuth is skipped.
+    if pipeline_cache
+        .get_compute_pipeline(pipeline.pipeline)
+        .is_none()
+    {
+        return;
+    }
+
+    bakes.states.retain(|_, state| {
+        let request = &state.request;
+        let (Some(heightmap), Some(horizon)) = (
+            gpu_images.get(request.heightmap),
+            gpu_images.get(request.horizon),
+        ) else {
+            return true;
+        };
+
+        let [min, max] = request.region;
+        let size = max.saturating_sub(min);
+        let layers = layer_count(request.coeffs, request.encoding);
+        let azimuth_count = request
+            .azimuths_per_frame
+            .min(AZIMUTHS as u32 - state.azimuth);
+        if size.cmpgt(UVec2::ZERO).all() {
+            let extent = Extent3d {
+                width: size.x,
+                height: size.y,
+                depth_or_array_layers: layers,
+            };
+            if state.azimuth == 0 {
+                let texture = render_device.create_texture(&TextureDescriptor {
+                    label: Some("horizon_bake_scratch"),
+                    size: extent,
+                    mip_level_count: 1,
+                    sample_count: 1,
+                    dimension: TextureDimension::D2,
+                    format: TextureFormat::R32Float,
+                    usage: TextureUsages::STORAGE_BINDING | TextureUsages::COPY_SRC,
+                    view_formats: &[],
+                });
+                let view = texture.create_view(&TextureViewDescriptor {
+                    dimension: Some(TextureViewDimension::D2Array),
+                    ..default()
+                });
+                state.scratch = Some((texture, view));
+            }
+            let (scratch, scratch_view) = state.scratch.as_ref().unwrap();
+            let mut params = UniformBuffer::from(HorizonBakeParams {
+                origin: min,
+                size,
+                map_size: UVec2::new(horizon.size.width, horizon.size.height),
+                azimuth_start: state.azimuth,
+                azimuth_count,
+                coeffs: request.coeffs,
+                layer_count: layers,
+                sectors: (request.encoding == HorizonEncoding::Sectors) as u32,
+                max_distance: request.max_distance as f32,
+            });
+            params.write_buffer(&render_device, &render_queue);
+            let bind_group = render_device.create_bind_group(
+                "horizon_bake_bind_group",
+                &pipeline_cache.get_bind_group_layout(&pipeline.layout),
+                &BindGroupEntries::sequential((
+                    &heightmap.texture_view,
+                    &pipeline.sampler,
+                    scratch_view,
+                    &params,
+                )),
+            );
+            let last = state.azimuth + azimuth_count == AZIMUTHS as u32;
+            bakes.passes.push(HorizonBakePass {
+                bind_group,
+                workgroups: (size + 7) / 8,
+                copy: last.then(|| (scratch.clone(), horizon.texture.clone(), min)),
+                extent,
+            });
+        }
+
+        state.azimuth += azimuth_count;
+        if state.azimuth < AZIMUTHS as u32 {
+            return true;
+        }
+        state.scratch = None;
+        let Some(pending) = state.pending.take() else {
+            return false;
+        };
+        state.request.region = pending;
+        state.azimuth = 0;
+        true
+    });
+}
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
+struct HorizonBakeLabel;
+
+struct HorizonBakeNode;
+
+impl render_graph::Node for HorizonBakeNode {
+    fn run(
+        &self,
+        _graph: &mut render_graph::RenderGraphContext,
+        render_context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), render_graph::NodeRunError> {
+        let bakes = world.resource::<HorizonBakes>();
+        if bakes.passes.is_empty() {
+            return Ok(());
+        }
+        let pipeline = world.resource::<HorizonBakePipeline>();
+        let Some(compute_pipeline) = world
+            .resource::<PipelineCache>()
+            .get_compute_pipeline(pipeline.pipeline)
+        else {
+            return Ok(());
+        };
+
+        let encoder = render_context.command_encoder();
+        {
+            let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor {
+                label: Some("horizon_bake"),
+                ..default()
+            });
+            pass.set_pipeline(compute_pipeline);
+            for bake in &bakes.passes {
+                pass.set_bind_group(0, &bake.bind_group, &[]);
+                pass.dispatch_workgroups(bake.workgroups.x, bake.workgroups.y, 1);
+            }
+        }
+        // Finished bakes replace their region of the horizon map at once.
+        for bake in &bakes.passes {
+            let Some((scratch, horizon, origin)) = &bake.copy else {
+                continue;
+            };
+            encoder.copy_texture_to_texture(
+                TexelCopyTextureInfo {
+                    texture: scratch,
+                    mip_level: 0,
+                    origin: Origin3d::ZERO,
+                    aspect: TextureAspect::All,
+                },
+                TexelCopyTextureInfo {
+                    texture: horizon,
+                    mip_level: 0,
+                    origin: Origin3d {
+                        x: origin.x,
+                        y: origin.y,
+                        z: 0,
+                    },
+                    aspect: TextureAspect::All,
+                },
+                bake.extent,
+            );
+        }
+        Ok(())
+    }
+}
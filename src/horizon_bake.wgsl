@@ -0,0 +1,90 @@
+// Bakes the horizon map, the GPU counterpart of `HorizonMap::bake` and `HorizonMap::bake_sectors`.
+// Every dispatch traces a batch of azimuths for a region of the map and adds them to the layers
+// of a scratch texture covering the region, which is copied into the map after the last batch.
+
+struct HorizonBakeParams {
+    origin: vec2<u32>,
+    size: vec2<u32>,
+    map_size: vec2<u32>,
+    azimuth_start: u32,
+    azimuth_count: u32,
+    coeffs: u32,
+    layer_count: u32,
+    // Non-zero for one layer per sector instead of FFT coefficients.
+    sectors: u32,
+    max_distance: f32,
+}
+
+@group(0) @binding(0) var heightmap_texture: texture_2d<f32>;
+@group(0) @binding(1) var heightmap_sampler: sampler;
+@group(0) @binding(2) var scratch_texture: texture_storage_2d_array<r32float, read_write>;
+@group(0) @binding(3) var<uniform> params: HorizonBakeParams;
+
+const AZIMUTHS: u32 = 360u;
+const MAX_BATCH: u32 = 32u;
+const TAU: f32 = 6.283185307179586;
+
+// Normalized height at the horizon map texel, the heightmap is resampled to the horizon map size.
+fn height(texel: vec2<f32>) -> f32 {
+    let uv = (texel + 0.5) / vec2<f32>(params.map_size);
+    return textureSampleLevel(heightmap_texture, heightmap_sampler, uv, 0.0).r;
+}
+
+// Tangent of the highest point toward the direction, in normalized height per texel.
+fn horizon_tangent(texel: vec2<f32>, direction: vec2<f32>) -> f32 {
+    let size = vec2<f32>(params.map_size);
+    let h = height(texel);
+    var tangent = 0.0;
+    var distance = 1.0;
+    loop {
+        if distance > params.max_distance {
+            break;
+        }
+        let p = texel + direction * distance;
+        if any(p < vec2(-0.5)) || any(p > size - 0.5) {
+            break;
+        }
+        tangent = max(tangent, (height(p) - h) / distance);
+        // Distant terrain only occludes when it is much higher, so the steps grow with the distance.
+        distance += max(1.0, distance / 32.0);
+    }
+    return tangent;
+}
+
+@compute @workgroup_size(8, 8, 1)
+fn bake(@builtin(global_invocation_id) id: vec3<u32>) {
+    if any(id.xy >= params.size) {
+        return;
+    }
+    let texel = params.origin + id.xy;
+
+    var tangents: array<f32, MAX_BATCH>;
+    for (var i = 0u; i < params.azimuth_count; i++) {
+        let angle = TAU * f32(params.azimuth_start + i) / f32(AZIMUTHS);
+        tangents[i] = horizon_tangent(vec2<f32>(texel), vec2(cos(angle), sin(angle)));
+    }
+
+    let frequencies = params.coeffs / 2u;
+    for (var layer = 0u; layer < params.layer_count; layer++) {
+        var value = 0.0;
+        if params.azimuth_start != 0u {
+            value = textureLoad(scratch_texture, id.xy, layer).r;
+        }
+        if params.sectors != 0u {
+            for (var i = 0u; i < params.azimuth_count; i++) {
+                if (params.azimuth_start + i) * params.layer_count / AZIMUTHS == layer {
+                    value = max(value, tangents[i]);
+                }
+            }
+        } else if layer <= 2u * frequencies {
+            // numpy.fft.rfft: X_k = sum x_n * exp(-2 pi i k n / N)
+            let imaginary = layer > frequencies;
+            let k = select(layer, layer - frequencies, imaginary);
+            for (var i = 0u; i < params.azimuth_count; i++) {
+                let angle = TAU * f32((k * (params.azimuth_start + i)) % AZIMUTHS) / f32(AZIMUTHS);
+                value += tangents[i] * select(cos(angle), -sin(angle), imaginary);
+            }
+        }
+        textureStore(scratch_texture, id.xy, layer, vec4(value, 0.0, 0.0, 0.0));
+    }
+}
@@ -0,0 +1,234 @@
+use bevy::{
+    asset::{AssetPath, embedded_asset, embedded_path},
+    pbr::{ExtendedMaterial, MaterialExtension},
+    prelude::*,
+    render::render_resource::{AsBindGroup, ShaderType},
+    shader::ShaderRef,
+};
+
+use crate::{
+    Clipmap,
+    horizon::{ClipmapHorizon, HorizonEncoding, HorizonShadow},
+    levels::ClipmapLevels,
+};
+
+/// Most directional lights with their own [`HorizonShadow`], `MAX_LIGHTS` in `horizon.wgsl`.
+const MAX_LIGHTS: usize = 10;
+
+/// Shadows a mesh by the horizon map of a [`Clipmap`], so objects in valleys are not lit by a sun
+/// below the ridges. Extends a [`StandardMaterial`] with [`ExtendedMaterial`].
+/// The horizon is sampled at the world position of the fragment as if it was on the terrain,
+/// objects high above the terrain are shadowed too much.
+/// Only the forward renderer applies the horizon, it is synced from the clipmap every frame.
+#[derive(Asset, AsBindGroup, Reflect, Debug, Clone, PartialEq)]
+#[bind_group_data(HorizonShadowKey)]
+pub struct HorizonShadowMaterial {
+    /// Clipmap whose horizon map shadows the mesh.
+    pub clipmap: Entity,
+    #[texture(104, dimension = "2d_array")]
+    #[sampler(105)]
+    horizon: Option<Handle<Image>>,
+    #[uniform(106)]
+    horizon_coeffs: u32,
+    horizon_encoding: HorizonEncoding,
+    #[uniform(127)]
+    horizon_ao: f32,
+    #[uniform(128)]
+    horizon_shadow: GpuHorizonShadow,
+    #[uniform(129)]
+    horizon_lights: GpuHorizonLights,
+    #[uniform(100)]
+    height_range: f32,
+    #[uniform(101)]
+    world_size: Vec2,
+}
+
+impl HorizonShadowMaterial {
+    /// Material shadowed by the horizon of the `clipmap` entity.
+    pub fn new(clipmap: Entity) -> Self {
+        Self {
+            clipmap,
+            horizon: None,
+            horizon_coeffs: 0,
+            horizon_encoding: HorizonEncoding::Fft,
+            horizon_ao: 0.0,
+            horizon_shadow: GpuHorizonShadow::new(None),
+            horizon_lights: GpuHorizonLights::default(),
+            height_range: 0.0,
+            world_size: Vec2::ONE,
+        }
+    }
+}
+
+#[derive(ShaderType, Reflect, Clone, Copy, Debug, PartialEq)]
+pub(crate) struct GpuHorizonShadow {
+    softness: f32,
+    bias: f32,
+    strength: f32,
+}
+
+impl GpuHorizonShadow {
+    /// Shadow settings of the clipmap horizon, the default ones without a horizon.
+    pub(crate) fn new(horizon: Option<&ClipmapHorizon>) -> Self {
+        let shadow = horizon.map_or_else(HorizonShadow::default, |horizon| horizon.shadow);
+        Self {
+            softness: shadow.softness,
+            bias: shadow.bias,
+            strength: shadow.strength,
+        }
+    }
+}
+
+#[derive(ShaderType, Reflect, Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct GpuHorizonLight {
+    direction: Vec3,
+    softness: f32,
+    bias: f32,
+    strength: f32,
+}
+
+#[derive(ShaderType, Reflect, Clone, Debug, Default, PartialEq)]
+pub(crate) struct GpuHorizonLights {
+    lights: [GpuHorizonLight; MAX_LIGHTS],
+    count: u32,
+}
+
+impl GpuHorizonLights {
+    /// Directional lights with a [`HorizonShadow`], the shader finds them by their direction.
+    /// Lights past [`MAX_LIGHTS`] are left out.
+    pub(crate) fn new<'a>(
+        lights: impl IntoIterator<Item = (&'a GlobalTransform, &'a HorizonShadow)>,
+    ) -> Self {
+        let mut gpu = Self::default();
+        for (gpu_light, (transform, shadow)) in gpu.lights.iter_mut().zip(lights) {
+            *gpu_light = GpuHorizonLight {
+                direction: transform.back().as_vec3(),
+                softness: shadow.softness,
+                bias: shadow.bias,
+                strength: shadow.strength,
+            };
+            gpu.count += 1;
+        }
+        gpu
+    }
+}
+
+#[repr(C)]
+#[derive(Eq, PartialEq, Hash, Copy, Clone)]
+pub struct HorizonShadowKey {
+    horizon: Option<HorizonEncoding>,
+}
+
+impl From<&HorizonShadowMaterial> for HorizonShadowKey {
+    fn from(material: &HorizonShadowMaterial) -> Self {
+        Self {
+            horizon: material
+                .horizon
+                .is_some()
+                .then_some(material.horizon_encoding),
+        }
+    }
+}
+
+impl MaterialExtension for HorizonShadowMaterial {
+    fn fragment_shader() -> ShaderRef {
+        ShaderRef::Path(
+            AssetPath::from_path_buf(embedded_path!("horizon_shadow.wgsl")).with_source("embedded"),
+        )
+    }
+
+    fn specialize(
+        _: &bevy::pbr::MaterialExtensionPipeline,
+        descriptor: &mut bevy::render::render_resource::RenderPipelineDescriptor,
+        _: &bevy::mesh::MeshVertexBufferLayoutRef,
+        key: bevy::pbr::MaterialExtensionKey<Self>,
+    ) -> std::result::Result<(), bevy::render::render_resource::SpecializedMeshPipelineError> {
+        if let Some(encoding) = key.bind_group_data.horizon
+            && let Some(fragment) = descriptor.fragment.as_mut()
+        {
+            fragment
+                .shader_defs
+                .extend(encoding.shader_defs().iter().map(|&def| def.into()));
+        }
+        Ok(())
+    }
+}
+
+pub(crate) struct HorizonShadowPlugin;
+
+impl Plugin for HorizonShadowPlugin {
+    fn build(&self, app: &mut App) {
+        embedded_asset!(app, "horizon_shadow.wgsl");
+
+        app.add_plugins(MaterialPlugin::<
+            ExtendedMaterial<StandardMaterial, HorizonShadowMaterial>,
+        >::default())
+            .init_resource::<HorizonLights>()
+            .add_systems(
+                Update,
+                (
+                    collect_horizon_lights,
+                    update_horizon_shadows.after(collect_horizon_lights),
+                ),
+            );
+    }
+}
+
+/// Directional lights with a [`HorizonShadow`], shared by the terrain and shadow materials.
+#[derive(Resource, Default)]
+pub(crate) struct HorizonLights(pub(crate) GpuHorizonLights);
+
+pub(crate) fn collect_horizon_lights(
+    lights: Query<(&GlobalTransform, &HorizonShadow), With<DirectionalLight>>,
+    mut horizon_lights: ResMut<HorizonLights>,
+) {
+    let count = lights.iter().len();
+    if count > MAX_LIGHTS {
+        warn_once!("{count} directional lights have a HorizonShadow, only {MAX_LIGHTS} are used");
+    }
+    let gpu = GpuHorizonLights::new(lights);
+    // Only changes are written, so the grid materials are synced when the lights change.
+    if horizon_lights.0 != gpu {
+        horizon_lights.0 = gpu;
+    }
+}
+
+/// Copies the horizon map, the shadow settings and the terrain bounds of the clipmaps into their
+/// shadow materials.
+fn update_horizon_shadows(
+    mut materials: ResMut<Assets<ExtendedMaterial<StandardMaterial, HorizonShadowMaterial>>>,
+    clipmaps: Query<(&Clipmap, Option<&ClipmapLevels>)>,
+    horizon_lights: Res<HorizonLights>,
+    images: Res<Assets<Image>>,
+) {
+    let ids = materials.ids().collect::<Vec<_>>();
+    for id in ids {
+        let material = &materials.get(id).unwrap().extension;
+        let Ok((clipmap, levels)) = clipmaps.get(material.clipmap) else {
+            continue;
+        };
+        let size = match levels {
+            Some(levels) => levels.size,
+            None => match images.get(&clipmap.heightmap) {
+                Some(image) => image.size(),
+                None => continue,
+            },
+        };
+        let horizon = clipmap.horizon.as_ref();
+        let synced = HorizonShadowMaterial {
+            clipmap: material.clipmap,
+            horizon: horizon.map(|horizon| horizon.texture.clone()),
+            horizon_coeffs: horizon.map_or(0, |horizon| horizon.coeffs),
+            horizon_encoding: horizon.map_or(HorizonEncoding::Fft, |horizon| horizon.encoding),
+            horizon_ao: horizon.map_or(0.0, |horizon| horizon.ao),
+            horizon_shadow: GpuHorizonShadow::new(horizon),
+            horizon_lights: horizon_lights.0.clone(),
+            height_range: clipmap.max - clipmap.min,
+            world_size: size.as_vec2() * clipmap.texel_size,
+        };
+        // Only changed materials are modified, so their bind groups are not prepared every frame.
+        if *material != synced {
+            materials.get_mut(id).unwrap().extension = synced;
+        }
+    }
+}
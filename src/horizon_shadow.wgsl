@@ -0,0 +1,33 @@
+// Forward fragment of `HorizonShadowMaterial`, the standard PBR fragment lit like the terrain.
+
+#import bevy_pbr::{
+    forward_io::{VertexOutput, FragmentOutput},
+    pbr_fragment::pbr_input_from_standard_material,
+    pbr_functions::{alpha_discard, main_pass_post_lighting_processing},
+    pbr_types::STANDARD_MATERIAL_FLAGS_UNLIT_BIT,
+}
+#import bevy_clipmap::lighting::apply_pbr_lighting
+
+@group(#{MATERIAL_BIND_GROUP}) @binding(100) var<uniform> height_range: f32;
+@group(#{MATERIAL_BIND_GROUP}) @binding(101) var<uniform> world_size: vec2<f32>;
+
+@fragment
+fn fragment(
+    in: VertexOutput,
+    @builtin(front_facing) is_front: bool,
+) -> FragmentOutput {
+    var pbr_input = pbr_input_from_standard_material(in, is_front);
+    pbr_input.material.base_color = alpha_discard(pbr_input.material, pbr_input.material.base_color);
+
+    var out: FragmentOutput;
+    if (pbr_input.material.flags & STANDARD_MATERIAL_FLAGS_UNLIT_BIT) == 0u {
+        // Same mapping as the terrain, which is centered at the origin.
+        let uv = in.world_position.xz / world_size + 0.5;
+        out.color = apply_pbr_lighting(pbr_input, uv, height_range);
+    } else {
+        out.color = pbr_input.material.base_color;
+    }
+    out.color = main_pass_post_lighting_processing(pbr_input, out.color);
+
+    return out;
+}
@@ -0,0 +1,272 @@
+use std::io::{self, Write};
+
+use bevy::prelude::*;
+
+const IDENTIFIER: [u8; 12] = [
+    0xab, 0x4b, 0x54, 0x58, 0x20, 0x32, 0x30, 0xbb, 0x0d, 0x0a, 0x1a, 0x0a,
+];
+
+/// KTXwriter value of the pyktx version `convert/clipmap.py` uses, so both tools write the same bytes.
+const WRITER: &[u8] = b"Unidentified app / libktx v4.4.2\0";
+
+const FLOAT: u8 = 0x80;
+const SIGNED: u8 = 0x40;
+
+/// One channel of a texel in the data format descriptor.
+pub(crate) struct Ktx2Sample {
+    pub(crate) channel: u8,
+    pub(crate) bit_offset: u16,
+    pub(crate) bit_length: u8,
+    /// Float and signed qualifiers.
+    pub(crate) qualifiers: u8,
+    pub(crate) lower: u32,
+    pub(crate) upper: u32,
+}
+
+/// Uncompressed texel format of a KTX2 file.
+pub(crate) struct Ktx2Format {
+    pub(crate) vk_format: u32,
+    pub(crate) type_size: u32,
+    pub(crate) texel_bytes: u8,
+    pub(crate) samples: &'static [Ktx2Sample],
+}
+
+pub(crate) const R16_UNORM: Ktx2Format = Ktx2Format {
+    vk_format: 70,
+    type_size: 2,
+    texel_bytes: 2,
+    samples: &[Ktx2Sample {
+        channel: 0,
+        bit_offset: 0,
+        bit_length: 16,
+        qualifiers: 0,
+        lower: 0,
+        upper: 0xffff,
+    }],
+};
+
+pub(crate) const R32_SFLOAT: Ktx2Format = Ktx2Format {
+    vk_format: 100,
+    type_size: 4,
+    texel_bytes: 4,
+    samples: &[Ktx2Sample {
+        channel: 0,
+        bit_offset: 0,
+        bit_length: 32,
+        qualifiers: FLOAT | SIGNED,
+        lower: 0xbf80_0000,
+        upper: 0x3f80_0000,
+    }],
+};
+
+pub(crate) const R16G16B16A16_SFLOAT: Ktx2Format = Ktx2Format {
+    vk_format: 97,
+    type_size: 2,
+    texel_bytes: 8,
+    samples: &[
+        half_sample(0, 0),
+        half_sample(1, 16),
+        half_sample(2, 32),
+        // Alpha is channel 15 of the RGBSDA color model.
+        half_sample(15, 48),
+    ],
+};
+
+const fn half_sample(channel: u8, bit_offset: u16) -> Ktx2Sample {
+    Ktx2Sample {
+        channel,
+        bit_offset,
+        bit_length: 16,
+        qualifiers: FLOAT | SIGNED,
+        lower: 0xbf80_0000,
+        upper: 0x3f80_0000,
+    }
+}
+
+/// Writes a single level, single layer KTX2 file with the same layout libktx produces.
+pub(crate) fn write_ktx2(
+    writer: &mut impl Write,
+    format: &Ktx2Format,
+    size: UVec2,
+    data: &[u8],
+) -> io::Result<()> {
+    write_ktx2_header(writer, format, size, None, data.len())?;
+    writer.write_all(data)
+}
+
+/// Writes everything of a single level KTX2 file up to the texel data.
+/// `layers` is the layer count of an array texture, the data of the layers must follow one by one.
+pub(crate) fn write_ktx2_header(
+    writer: &mut impl Write,
+    format: &Ktx2Format,
+    size: UVec2,
+    layers: Option<u32>,
+    data_length: usize,
+) -> io::Result<()> {
+    let mut dfd = vec![];
+    let block_size = 24 + 16 * format.samples.len() as u32;
+    dfd.extend((4 + block_size).to_le_bytes());
+    // Khronos vendor, basic descriptor type.
+    dfd.extend(0u32.to_le_bytes());
+    // Version 1.3 of the descriptor.
+    dfd.extend(2u16.to_le_bytes());
+    dfd.extend((block_size as u16).to_le_bytes());
+    // RGBSDA color model, BT.709 primaries, linear transfer, straight alpha.
+    dfd.extend([1, 1, 1, 0]);
+    dfd.extend([0; 4]);
+    dfd.extend([format.texel_bytes, 0, 0, 0, 0, 0, 0, 0]);
+    for sample in format.samples {
+        dfd.extend(sample.bit_offset.to_le_bytes());
+        dfd.push(sample.bit_length - 1);
+        dfd.push(sample.channel | sample.qualifiers);
+        dfd.extend([0; 4]);
+        dfd.extend(sample.lower.to_le_bytes());
+        dfd.extend(sample.upper.to_le_bytes());
+    }
+
+    let mut kvd = vec![];
+    let entry_length = b"KTXwriter\0".len() + WRITER.len();
+    kvd.extend((entry_length as u32).to_le_bytes());
+    kvd.extend(b"KTXwriter\0");
+    kvd.extend(WRITER);
+    kvd.resize(kvd.len().next_multiple_of(4), 0);
+
+    let header_size = 12 + 9 * 4 + 4 * 4 + 2 * 8 + 3 * 8;
+    let dfd_offset = header_size;
+    let kvd_offset = dfd_offset + dfd.len();
+    // Level data is aligned to the least common multiple of the texel size and 4.
+    let alignment = (format.texel_bytes as usize).max(4).next_multiple_of(4);
+    let data_offset = (kvd_offset + kvd.len()).next_multiple_of(alignment);
+
+    writer.write_all(&IDENTIFIER)?;
+    for value in [
+        format.vk_format,
+        format.type_size,
+        size.x,
+        size.y,
+        0,
+        layers.unwrap_or(0),
+        1,
+        1,
+        0,
+    ] {
+        writer.write_all(&value.to_le_bytes())?;
+    }
+    for value in [
+        dfd_offset as u32,
+        dfd.len() as u32,
+        kvd_offset as u32,
+        kvd.len() as u32,
+    ] {
+        writer.write_all(&value.to_le_bytes())?;
+    }
+    for value in [
+        0u64,
+        0,
+        data_offset as u64,
+        data_length as u64,
+        data_length as u64,
+    ] {
+        writer.write_all(&value.to_le_bytes())?;
+    }
+    writer.write_all(&dfd)?;
+    writer.write_all(&kvd)?;
+    writer.write_all(&vec![0; data_offset - kvd_offset - kvd.len()])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+    }
+
+    #[test]
+    fn r16_unorm_file() {
+        let data = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+        let mut file = vec![];
+        write_ktx2(&mut file, &R16_UNORM, UVec2::new(3, 2), &data).unwrap();
+
+        assert_eq!(file[..12], IDENTIFIER);
+        let header = (0..9)
+            .map(|i| u32_at(&file, 12 + i * 4))
+            .collect::<Vec<_>>();
+        // Format, type size, width, height, depth, layers, faces, levels, supercompression.
+        assert_eq!(header, [70, 2, 3, 2, 0, 0, 1, 1, 0]);
+
+        let (dfd_offset, dfd_length) = (u32_at(&file, 48) as usize, u32_at(&file, 52) as usize);
+        let (kvd_offset, kvd_length) = (u32_at(&file, 56) as usize, u32_at(&file, 60) as usize);
+        assert_eq!((dfd_offset, dfd_length), (104, 44));
+        assert_eq!(kvd_offset, dfd_offset + dfd_length);
+        // No supercompression global data.
+        assert_eq!((u64_at(&file, 64), u64_at(&file, 72)), (0, 0));
+
+        #[rustfmt::skip]
+        let dfd: [u8; 44] = [
+            44, 0, 0, 0,
+            // Khronos vendor and basic descriptor type, version 1.3, block size.
+            0, 0, 0, 0, 2, 0, 40, 0,
+            // RGBSDA, BT.709, linear, straight alpha.
+            1, 1, 1, 0,
+            0, 0, 0, 0,
+            2, 0, 0, 0, 0, 0, 0, 0,
+            // Red channel, 16 bits at offset 0, unsigned normalized.
+            0, 0, 15, 0, 0, 0, 0, 0,
+            0, 0, 0, 0, 0xff, 0xff, 0, 0,
+        ];
+        assert_eq!(file[dfd_offset..kvd_offset], dfd);
+
+        let kvd = &file[kvd_offset..kvd_offset + kvd_length];
+        let entry_length = u32_at(kvd, 0) as usize;
+        assert_eq!(entry_length, 10 + WRITER.len());
+        assert_eq!(&kvd[4..14], b"KTXwriter\0");
+        assert_eq!(&kvd[14..4 + entry_length], WRITER);
+        assert_eq!(kvd_length, (4 + entry_length).next_multiple_of(4));
+        assert!(kvd[4 + entry_length..].iter().all(|&byte| byte == 0));
+
+        let data_offset = u64_at(&file, 80) as usize;
+        assert_eq!(data_offset, (kvd_offset + kvd_length).next_multiple_of(4));
+        assert_eq!((u64_at(&file, 88), u64_at(&file, 96)), (12, 12));
+        assert_eq!(file[data_offset..], data);
+    }
+
+    #[test]
+    fn rgba16f_array_file() {
+        let data = [0; 2 * 2 * 8 * 3];
+        let mut file = vec![];
+        write_ktx2_header(
+            &mut file,
+            &R16G16B16A16_SFLOAT,
+            UVec2::new(2, 2),
+            Some(3),
+            data.len(),
+        )
+        .unwrap();
+        file.extend(data);
+
+        assert_eq!(u32_at(&file, 12), 97);
+        assert_eq!(u32_at(&file, 32), 3);
+        let dfd_offset = u32_at(&file, 48) as usize;
+        // Four samples of 16 bytes after the 24 byte block header.
+        assert_eq!(u32_at(&file, 52), 4 + 24 + 4 * 16);
+        let samples = &file[dfd_offset + 28..dfd_offset + 28 + 4 * 16];
+        for (sample, (channel, bit_offset)) in
+            samples.chunks(16).zip([(0, 0), (1, 16), (2, 32), (15, 48)])
+        {
+            assert_eq!(u16::from_le_bytes([sample[0], sample[1]]), bit_offset);
+            assert_eq!(sample[2], 15);
+            assert_eq!(sample[3], channel | FLOAT | SIGNED);
+            assert_eq!(u32_at(sample, 8), 0xbf80_0000);
+            assert_eq!(u32_at(sample, 12), 0x3f80_0000);
+        }
+        // Level data is aligned to the 8 byte texels.
+        let data_offset = u64_at(&file, 80) as usize;
+        assert_eq!(data_offset % 8, 0);
+        assert_eq!(file.len(), data_offset + data.len());
+    }
+}
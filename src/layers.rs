@@ -0,0 +1,42 @@
+use bevy::prelude::*;
+
+/// Maximum number of terrain layers.
+pub const MAX_LAYERS: usize = 16;
+
+/// Splat-mapped terrain layers replacing `Clipmap::color`.
+/// All textures are 2D arrays and should use a repeating sampler, 2D images can be converted
+/// with [`Image::reinterpret_stacked_2d_as_array`].
+#[derive(Component, Clone, Debug)]
+pub struct ClipmapLayers {
+    /// Weights of the layers stretched across the whole heightmap.
+    /// Every RGBA array layer holds the weights of four terrain layers.
+    pub control: Handle<Image>,
+
+    /// Albedo of every layer.
+    pub albedo: Handle<Image>,
+
+    /// Tangent space normal map of every layer.
+    pub normal: Handle<Image>,
+
+    /// Perceptual roughness of every layer in the red channel.
+    pub roughness: Handle<Image>,
+
+    /// Size of one repetition of every layer in world units, at most [`MAX_LAYERS`].
+    pub tiling: Vec<f32>,
+}
+
+impl ClipmapLayers {
+    /// Tiling packed for the shader.
+    pub(crate) fn packed_tiling(&self) -> [Vec4; MAX_LAYERS / 4] {
+        let mut packed = [Vec4::ONE; MAX_LAYERS / 4];
+        for (i, tiling) in self.tiling.iter().take(MAX_LAYERS).enumerate() {
+            packed[i / 4][i % 4] = *tiling;
+        }
+        packed
+    }
+
+    /// Number of layers.
+    pub(crate) fn count(&self) -> u32 {
+        self.tiling.len().min(MAX_LAYERS) as u32
+    }
+}
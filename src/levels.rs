@@ -0,0 +1,234 @@
+use bevy::{
+    asset::RenderAssetUsages,
+    prelude::*,
+    render::render_resource::{
+        Extent3d, TextureDimension, TextureFormat, TextureViewDescriptor, TextureViewDimension,
+    },
+};
+
+use crate::{
+    height::{read_texel, write_texel},
+    upload::ImageUploads,
+};
+
+/// Per-level heightmap textures following the clipmap target.
+/// Level `n` holds a window of `resolution` texels of the heightmap downscaled `2^n` times,
+/// addressed toroidally so only newly exposed texels have to be written when the target moves.
+#[derive(Component)]
+pub struct ClipmapLevels {
+    /// Texture array with one layer per level.
+    pub(crate) image: Handle<Image>,
+
+    /// Size of the full heightmap in level 0 texels, zero while unknown.
+    pub(crate) size: UVec2,
+
+    /// Width and height of every level window.
+    resolution: u32,
+
+    /// Pending regions are split into chunks of this size.
+    chunk_size: u32,
+
+    /// Origin of the resident window of every level.
+    windows: Vec<Option<IVec2>>,
+
+    /// Regions of the windows waiting for data.
+    pub(crate) pending: Vec<(u32, IRect)>,
+}
+
+impl ClipmapLevels {
+    pub(crate) fn new(
+        images: &mut Assets<Image>,
+        resolution: u32,
+        levels: u32,
+        chunk_size: u32,
+    ) -> Self {
+        let mut image = Image::new_fill(
+            Extent3d {
+                width: resolution,
+                height: resolution,
+                depth_or_array_layers: levels,
+            },
+            TextureDimension::D2,
+            &[0, 0],
+            TextureFormat::R16Unorm,
+            RenderAssetUsages::default(),
+        );
+        image.texture_view_descriptor = Some(TextureViewDescriptor {
+            dimension: Some(TextureViewDimension::D2Array),
+            ..Default::default()
+        });
+
+        Self {
+            image: images.add(image),
+            size: UVec2::ZERO,
+            resolution,
+            chunk_size,
+            windows: vec![None; levels as usize],
+            pending: vec![],
+        }
+    }
+
+    /// Sets the size of the heightmap, all levels are requested again.
+    pub(crate) fn resize(&mut self, size: UVec2) {
+        self.size = size;
+        self.windows.fill(None);
+        self.pending.clear();
+    }
+
+    /// Resident windows of all levels.
+    pub(crate) fn windows(&self) -> Vec<Option<IRect>> {
+        (0..self.levels()).map(|level| self.window(level)).collect()
+    }
+
+    /// Number of levels.
+    pub(crate) fn levels(&self) -> u32 {
+        self.windows.len() as u32
+    }
+
+    /// Size of the heightmap at the level.
+    pub(crate) fn level_size(&self, level: u32) -> IVec2 {
+        (self.size >> level).max(UVec2::ONE).as_ivec2()
+    }
+
+    /// Resident window of the level clipped to the heightmap.
+    pub(crate) fn window(&self, level: u32) -> Option<IRect> {
+        let origin = self.windows[level as usize]?;
+        let window = IRect::from_corners(origin, origin + self.resolution as i32);
+        Some(window.intersect(IRect::from_corners(IVec2::ZERO, self.level_size(level))))
+    }
+
+    /// Moves the windows to be centered at the target, given in level 0 texels.
+    /// Newly exposed regions are queued to `pending`.
+    pub(crate) fn follow(&mut self, target: Vec2) {
+        let resolution = self.resolution as i32;
+        for level in 0..self.levels() {
+            let center = (target / 2u32.pow(level) as f32).floor().as_ivec2();
+            let origin = center - resolution / 2;
+            let old = self.windows[level as usize].replace(origin);
+            if old == Some(origin) {
+                continue;
+            }
+
+            let bounds = IRect::from_corners(IVec2::ZERO, self.level_size(level));
+            let old = old.map(|old| IRect::from_corners(old, old + resolution));
+            let new = IRect::from_corners(origin, origin + resolution);
+            for rect in exposed(old, new) {
+                let rect = rect.intersect(bounds);
+                if rect.is_empty() {
+                    continue;
+                }
+                for chunk in chunks(rect, self.chunk_size as i32) {
+                    self.pending.push((level, chunk));
+                }
+            }
+        }
+
+        let windows = self.windows();
+        self.pending.retain_mut(|(level, rect)| {
+            let Some(window) = windows[*level as usize] else {
+                return false;
+            };
+            *rect = rect.intersect(window);
+            !rect.is_empty()
+        });
+    }
+
+    /// Normalized height at the level 0 texel, taken from the finest level holding it.
+    pub(crate) fn height(&self, image: &Image, texel: IVec2) -> f32 {
+        let data = image.data.as_deref().unwrap_or_default();
+        for level in 0..self.levels() {
+            let texel = texel.div_euclid(IVec2::splat(1 << level));
+            let Some(window) = self.window(level) else {
+                continue;
+            };
+            let resident = |rect: &IRect| (texel.cmpge(rect.min) & texel.cmplt(rect.max)).all();
+            if !resident(&window)
+                || self
+                    .pending
+                    .iter()
+                    .any(|(pending, rect)| *pending == level && resident(rect))
+            {
+                continue;
+            }
+            let i = self.offset(level, texel);
+            return read_texel(TextureFormat::R16Unorm, &data[i..i + 2]).unwrap_or_default();
+        }
+        0.0
+    }
+
+    /// Writes normalized heights of the level region given row by row, and queues the upload.
+    pub(crate) fn write(
+        &self,
+        image: &mut Image,
+        uploads: &mut ImageUploads,
+        level: u32,
+        rect: IRect,
+        heights: &[f32],
+    ) {
+        let data = image.data.as_mut().unwrap();
+        let width = rect.width() as usize;
+        for y in rect.min.y..rect.max.y {
+            for x in rect.min.x..rect.max.x {
+                let i = self.offset(level, IVec2::new(x, y));
+                let height = heights[(y - rect.min.y) as usize * width + (x - rect.min.x) as usize];
+                write_texel(TextureFormat::R16Unorm, height, &mut data[i..i + 2]);
+            }
+        }
+
+        let resolution = self.resolution as i32;
+        for part in chunks(rect, resolution) {
+            let min = part.min.rem_euclid(IVec2::splat(resolution)).as_uvec2();
+            uploads.push(
+                &self.image,
+                image,
+                0,
+                level,
+                min,
+                min + part.size().as_uvec2(),
+            );
+        }
+    }
+
+    /// Byte offset of the level texel in the wrapped window.
+    fn offset(&self, level: u32, texel: IVec2) -> usize {
+        let resolution = self.resolution as usize;
+        let wrapped = texel.rem_euclid(IVec2::splat(self.resolution as i32));
+        ((level as usize * resolution + wrapped.y as usize) * resolution + wrapped.x as usize) * 2
+    }
+}
+
+/// Part of the new window not covered by the old one.
+fn exposed(old: Option<IRect>, new: IRect) -> Vec<IRect> {
+    let Some(old) = old.filter(|old| !old.intersect(new).is_empty()) else {
+        return vec![new];
+    };
+
+    let mut rects = vec![];
+    if new.min.x < old.min.x {
+        rects.push(IRect::new(new.min.x, new.min.y, old.min.x, new.max.y));
+    }
+    if new.max.x > old.max.x {
+        rects.push(IRect::new(old.max.x, new.min.y, new.max.x, new.max.y));
+    }
+    let (min_x, max_x) = (new.min.x.max(old.min.x), new.max.x.min(old.max.x));
+    if new.min.y < old.min.y {
+        rects.push(IRect::new(min_x, new.min.y, max_x, old.min.y));
+    }
+    if new.max.y > old.max.y {
+        rects.push(IRect::new(min_x, old.max.y, max_x, new.max.y));
+    }
+    rects
+}
+
+/// Splits the rectangle along a grid of the given cell size.
+pub(crate) fn chunks(rect: IRect, size: i32) -> impl Iterator<Item = IRect> {
+    let min = rect.min.div_euclid(IVec2::splat(size));
+    let max = (rect.max - 1).div_euclid(IVec2::splat(size));
+    (min.y..=max.y).flat_map(move |y| {
+        (min.x..=max.x).map(move |x| {
+            let cell =
+                IRect::from_corners(IVec2::new(x, y) * size, IVec2::new(x + 1, y + 1) * size);
+            cell.intersect(rect)
+        })
+    })
+}
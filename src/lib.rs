@@ -9,11 +9,57 @@ use bevy::{
     light::NotShadowCaster,
     mesh::{Indices, PrimitiveTopology},
     pbr::{ExtendedMaterial, MaterialExtension},
+    picking::Pickable,
     prelude::*,
     render::render_resource::AsBindGroup,
-    shader::ShaderRef,
+    shader::{ShaderRef, load_shader_library},
 };
 
+mod collider;
+mod edit;
+mod export;
+mod height;
+mod history;
+mod horizon;
+mod horizon_bake;
+mod horizon_shadow;
+mod ktx2;
+mod layers;
+mod levels;
+mod mip;
+mod noise;
+mod picking;
+mod raycast;
+mod rules;
+mod source;
+mod tiles;
+mod upload;
+
+use horizon_shadow::{GpuHorizonLights, GpuHorizonShadow, HorizonLights};
+use levels::ClipmapLevels;
+use rules::GpuMaterialRules;
+
+pub use collider::{ClipmapCollider, ClipmapHeightfield};
+pub use edit::{BrushMode, ClipmapBrush};
+#[cfg(feature = "png")]
+pub use export::write_heightmap_png;
+pub use export::{
+    ExportError, heightmap_u16, save_clipmap_heightmap, save_heightmap, write_heightmap_ktx2,
+    write_r16_ktx2,
+};
+pub use height::{ClipmapHeightQuery, ClipmapSample, HeightmapSampler};
+pub use history::{ClipmapHistory, ClipmapRedo, ClipmapTexture, ClipmapUndo};
+pub use horizon::{AZIMUTHS, ClipmapHorizon, HorizonEncoding, HorizonMap, HorizonShadow};
+pub use horizon_bake::ClipmapHorizonBake;
+pub use horizon_shadow::HorizonShadowMaterial;
+pub use layers::{ClipmapLayers, MAX_LAYERS};
+pub use noise::{NoiseKind, NoiseSource};
+pub use picking::ClipmapPickingPlugin;
+pub use raycast::{ClipmapRayHit, ClipmapRaycast};
+pub use rules::{ClipmapMaterialRules, MAX_RULES, MaterialRule};
+pub use source::{ClipmapHeightSource, HeightSource, HeightSourceContext, TextureSource};
+pub use tiles::TileSource;
+
 pub struct ClipmapPlugin;
 
 struct ClipmapPart {
@@ -47,13 +93,41 @@ struct ClipmapParts {
 
 impl Plugin for ClipmapPlugin {
     fn build(&self, app: &mut App) {
+        load_shader_library!(app, "horizon.wgsl");
+        load_shader_library!(app, "lighting.wgsl");
         embedded_asset!(app, "terrain.wgsl");
 
-        app.add_plugins(MaterialPlugin::<
-            ExtendedMaterial<StandardMaterial, GridMaterial>,
-        >::default())
-            .add_systems(PreUpdate, (init_clipmaps, init_grids))
-            .add_systems(Update, update_grids);
+        app.add_plugins((
+            MaterialPlugin::<ExtendedMaterial<StandardMaterial, GridMaterial>>::default(),
+            upload::UploadPlugin,
+            horizon_bake::HorizonBakePlugin,
+            horizon_shadow::HorizonShadowPlugin,
+        ))
+        .add_message::<ClipmapBrush>()
+        .add_message::<ClipmapUndo>()
+        .add_message::<ClipmapRedo>()
+        .add_systems(PreUpdate, (init_clipmaps, init_grids))
+        .add_systems(
+            Update,
+            (
+                (edit::apply_brushes, history::apply_history)
+                    .chain()
+                    .before(update_grids)
+                    .before(collider::update_heightfields),
+                horizon_bake::queue_horizon_bakes.after(history::apply_history),
+                update_grids,
+                update_grid_lights.after(horizon_shadow::collect_horizon_lights),
+                collider::update_heightfields,
+                mip::generate_heightmap_mips,
+                source::update_sources,
+            ),
+        );
+
+        #[cfg(feature = "avian3d")]
+        app.add_systems(
+            Update,
+            collider::update_colliders.after(collider::update_heightfields),
+        );
     }
 }
 
@@ -100,8 +174,18 @@ impl MeshBuilder {
     }
 
     fn build(self) -> Mesh {
+        // UVs and tangents are replaced in the shader, they only enable the texture and normal
+        // map inputs of the base material.
+        let uvs = self
+            .vertices
+            .iter()
+            .map(|v| [v[0], v[2]])
+            .collect::<Vec<_>>();
+        let tangents = vec![[1.0, 0.0, 0.0, 1.0]; self.vertices.len()];
         Mesh::new(PrimitiveTopology::TriangleList, RenderAssetUsages::all())
             .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, self.vertices)
+            .with_inserted_attribute(Mesh::ATTRIBUTE_UV_0, uvs)
+            .with_inserted_attribute(Mesh::ATTRIBUTE_TANGENT, tangents)
             .with_inserted_indices(Indices::U32(self.indices))
     }
 }
@@ -128,23 +212,66 @@ pub struct Clipmap {
     pub target: Entity,
 
     /// Color texture.
+    /// Ignored if the clipmap has [`ClipmapLayers`].
     pub color: Handle<Image>,
 
+    /// Base material of the terrain.
+    /// Its base color tints the terrain color and its roughness scales the layer roughness.
+    /// Its textures span the whole terrain like `color`, `uv_transform` tiles them.
+    pub base: StandardMaterial,
+
     /// Heightmap texture.
+    /// Ignored if the clipmap has a [`ClipmapHeightSource`].
     pub heightmap: Handle<Image>,
 
-    /// FFT-compressed horizon map texture.
-    pub horizon: Handle<Image>,
-
-    /// Number of FFT coefficients.
-    pub horizon_coeffs: u32,
+    /// Horizon map, without it the terrain has no horizon shadows.
+    /// The texture is replaced by the baked one if the clipmap has a [`ClipmapHorizonBake`].
+    pub horizon: Option<ClipmapHorizon>,
 
     /// Height bounds.
+    /// Sculpting with [`ClipmapBrush`] expands them when needed.
     pub min: f32,
     pub max: f32,
 
     /// Enable wireframe.
     pub wireframe: bool,
+
+    /// Sample terrain layers with triplanar projection to avoid stretching on cliffs.
+    pub triplanar: bool,
+
+    /// Width of the geomorphing region at the outer edge of each level, in grid cells.
+    /// Vertices in this region are blended toward the next coarser level to hide popping.
+    /// Zero disables geomorphing.
+    pub morph_width: f32,
+
+    /// Number of levels from the innermost one that cast shadows into shadow maps.
+    /// Zero disables terrain shadow casting, the terrain is still shadowed by its horizon map.
+    pub shadow_levels: u32,
+}
+
+impl Default for Clipmap {
+    fn default() -> Self {
+        Self {
+            half_width: 128,
+            levels: 7,
+            base_scale: 1.0,
+            texel_size: 1.0,
+            target: Entity::PLACEHOLDER,
+            color: Handle::default(),
+            base: StandardMaterial {
+                perceptual_roughness: 1.0,
+                ..Default::default()
+            },
+            heightmap: Handle::default(),
+            horizon: None,
+            min: 0.0,
+            max: 1.0,
+            wireframe: false,
+            triplanar: false,
+            morph_width: 16.0,
+            shadow_levels: 0,
+        }
+    }
 }
 
 #[derive(Component)]
@@ -159,12 +286,20 @@ impl ClipmapGrid {
     }
 }
 
+type ClipmapInitData = (
+    Entity,
+    &'static mut Clipmap,
+    Option<&'static ClipmapHeightSource>,
+    Option<&'static ClipmapHorizonBake>,
+);
+
 fn init_clipmaps(
     mut commands: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
-    clipmaps: Query<(Entity, &Clipmap), Added<Clipmap>>,
+    mut images: ResMut<Assets<Image>>,
+    clipmaps: Query<ClipmapInitData, Added<Clipmap>>,
 ) {
-    for (entity, clipmap) in clipmaps {
+    for (entity, mut clipmap, source, bake) in clipmaps {
         let builder_width = clipmap.half_width as i32 * 2;
         let filler_width = 2 - clipmap.half_width as i32 % 2;
         let square_width = (clipmap.half_width as i32 - filler_width) / 2;
@@ -217,6 +352,27 @@ fn init_clipmaps(
             },
         ));
 
+        if let Some(source) = source {
+            commands.entity(entity).insert(ClipmapLevels::new(
+                &mut images,
+                source.resolution,
+                source.source.levels(),
+                source.chunk_size,
+            ));
+        }
+
+        if let (Some(bake), Some(horizon)) = (bake, clipmap.horizon.as_mut()) {
+            // RGBA16F can't be baked in place, packed maps are baked unpacked.
+            if horizon.encoding == HorizonEncoding::PackedFft {
+                horizon.encoding = HorizonEncoding::Fft;
+            }
+            horizon.texture = images.add(horizon_bake::horizon_image(
+                bake.size,
+                horizon.coeffs,
+                horizon.encoding,
+            ));
+        }
+
         for level in 0..clipmap.levels {
             commands.entity(entity).with_child(ClipmapGrid {
                 level,
@@ -226,14 +382,24 @@ fn init_clipmaps(
     }
 }
 
+/// Clipmap components the grid materials are built from.
+type GridInitData = (
+    &'static Clipmap,
+    &'static ClipmapParts,
+    Option<&'static ClipmapLevels>,
+    Option<&'static ClipmapLayers>,
+    Option<&'static ClipmapMaterialRules>,
+);
+
 fn init_grids(
     mut commands: Commands,
     mut materials: ResMut<Assets<ExtendedMaterial<StandardMaterial, GridMaterial>>>,
-    clipmaps: Query<(&Clipmap, &ClipmapParts)>,
+    clipmaps: Query<GridInitData>,
     mut grids: Query<(Entity, &mut ClipmapGrid, &ChildOf), Added<ClipmapGrid>>,
+    horizon_lights: Res<HorizonLights>,
 ) {
     for (entity, mut grid, clipmap) in &mut grids {
-        let (clipmap, parts) = clipmaps.get(clipmap.parent()).unwrap();
+        let (clipmap, parts, levels, layers, rules) = clipmaps.get(clipmap.parent()).unwrap();
 
         let filler_width = 2 - clipmap.half_width as i32 % 2;
         let square_width = (clipmap.half_width as i32 - filler_width) / 2;
@@ -243,31 +409,25 @@ fn init_grids(
             Visibility::default(),
         ));
 
-        let terrain_material = materials.add(ExtendedMaterial {
-            base: StandardMaterial::default(),
-            extension: GridMaterial {
-                color: clipmap.color.clone(),
-                heightmap: clipmap.heightmap.clone(),
-                horizon: clipmap.horizon.clone(),
-                horizon_coeffs: clipmap.horizon_coeffs,
-                lod: grid.level,
-                texel_size: clipmap.texel_size,
-                minmax: Vec2 {
-                    x: clipmap.min,
-                    y: clipmap.max,
-                },
-                translation: Vec2::ZERO,
-                wireframe: 0,
-            },
-        });
+        let shadow_caster = grid.level < clipmap.shadow_levels;
 
-        let terrain_material_w = materials.add(ExtendedMaterial {
-            base: StandardMaterial::default(),
+        let grid_material = |wireframe| ExtendedMaterial {
+            base: clipmap.base.clone(),
             extension: GridMaterial {
                 color: clipmap.color.clone(),
                 heightmap: clipmap.heightmap.clone(),
-                horizon: clipmap.horizon.clone(),
-                horizon_coeffs: clipmap.horizon_coeffs,
+                horizon: clipmap
+                    .horizon
+                    .as_ref()
+                    .map(|horizon| horizon.texture.clone()),
+                horizon_coeffs: clipmap.horizon.as_ref().map_or(0, |horizon| horizon.coeffs),
+                horizon_encoding: clipmap
+                    .horizon
+                    .as_ref()
+                    .map_or(HorizonEncoding::Fft, |horizon| horizon.encoding),
+                horizon_ao: clipmap.horizon.as_ref().map_or(0.0, |horizon| horizon.ao),
+                horizon_shadow: GpuHorizonShadow::new(clipmap.horizon.as_ref()),
+                horizon_lights: horizon_lights.0.clone(),
                 lod: grid.level,
                 texel_size: clipmap.texel_size,
                 minmax: Vec2 {
@@ -275,9 +435,31 @@ fn init_grids(
                     y: clipmap.max,
                 },
                 translation: Vec2::ZERO,
-                wireframe: 1,
+                wireframe,
+                base_scale: clipmap.base_scale,
+                half_width: clipmap.half_width,
+                morph_width: if grid.level + 1 < clipmap.levels {
+                    clipmap.morph_width
+                } else {
+                    0.0
+                },
+                target: Vec2::ZERO,
+                levels: levels.map(|levels| levels.image.clone()),
+                levels_size: levels.map_or(UVec2::ZERO, |levels| levels.size),
+                control: layers.map(|layers| layers.control.clone()),
+                layer_albedo: layers.map(|layers| layers.albedo.clone()),
+                layer_normal: layers.map(|layers| layers.normal.clone()),
+                layer_roughness: layers.map(|layers| layers.roughness.clone()),
+                layer_tiling: layers
+                    .map_or([Vec4::ONE; MAX_LAYERS / 4], |layers| layers.packed_tiling()),
+                layer_count: layers.map_or(0, |layers| layers.count()),
+                rules: rules.map(GpuMaterialRules::from).unwrap_or_default(),
+                triplanar: clipmap.triplanar,
             },
-        });
+        };
+
+        let terrain_material = materials.add(grid_material(0));
+        let terrain_material_w = materials.add(grid_material(1));
 
         for xy in 0..4 * 4 {
             let x = xy % 4;
@@ -294,21 +476,25 @@ fn init_grids(
                 let mut e = c.spawn((
                     Mesh3d(parts.square.handle.clone()),
                     MeshMaterial3d(terrain_material.clone()),
-                    NotShadowCaster,
+                    Pickable::IGNORE,
                     Transform::from_xyz(
                         (x - 2) as f32 * square_width as f32 + offset_x,
                         0.0,
                         (y - 2) as f32 * square_width as f32 + offset_y,
                     ),
                     NoAutoAabb,
-                    parts.square.aabb.clone(),
+                    parts.square.aabb,
                 ));
+                if !shadow_caster {
+                    e.insert(NotShadowCaster);
+                }
                 if clipmap.wireframe {
                     e.with_child((
                         Mesh3d(parts.square.handle.clone()),
                         MeshMaterial3d(terrain_material_w.clone()),
+                        NotShadowCaster,
                         NoAutoAabb,
-                        parts.square.aabb.clone(),
+                        parts.square.aabb,
                     ));
                 }
             });
@@ -319,7 +505,7 @@ fn init_grids(
                 let mut e = c.spawn((
                     Mesh3d(parts.center.handle.clone()),
                     MeshMaterial3d(terrain_material.clone()),
-                    NotShadowCaster,
+                    Pickable::IGNORE,
                     Transform::from_xyz(
                         -2.0 * square_width as f32,
                         0.0,
@@ -328,10 +514,14 @@ fn init_grids(
                     NoAutoAabb,
                     parts.center.aabb,
                 ));
+                if !shadow_caster {
+                    e.insert(NotShadowCaster);
+                }
                 if clipmap.wireframe {
                     e.with_child((
                         Mesh3d(parts.center.handle.clone()),
                         MeshMaterial3d(terrain_material_w.clone()),
+                        NotShadowCaster,
                         NoAutoAabb,
                         parts.center.aabb,
                     ));
@@ -342,7 +532,7 @@ fn init_grids(
                 let mut e = c.spawn((
                     Mesh3d(parts.filler.handle.clone()),
                     MeshMaterial3d(terrain_material.clone()),
-                    NotShadowCaster,
+                    Pickable::IGNORE,
                     Transform::from_xyz(
                         -2.0 * square_width as f32,
                         0.0,
@@ -351,10 +541,14 @@ fn init_grids(
                     NoAutoAabb,
                     parts.filler.aabb,
                 ));
+                if !shadow_caster {
+                    e.insert(NotShadowCaster);
+                }
                 if clipmap.wireframe {
                     e.with_child((
                         Mesh3d(parts.filler.handle.clone()),
                         MeshMaterial3d(terrain_material_w.clone()),
+                        NotShadowCaster,
                         NoAutoAabb,
                         parts.filler.aabb,
                     ));
@@ -364,16 +558,20 @@ fn init_grids(
                 let mut e = c.spawn((
                     Mesh3d(parts.stitch.handle.clone()),
                     MeshMaterial3d(terrain_material.clone()),
-                    NotShadowCaster,
+                    Pickable::IGNORE,
                     Transform::from_xyz(-square_width as f32, 0.0, -square_width as f32)
                         .with_scale(Vec3::splat(0.5)),
                     NoAutoAabb,
                     parts.stitch.aabb,
                 ));
+                if !shadow_caster {
+                    e.insert(NotShadowCaster);
+                }
                 if clipmap.wireframe {
                     e.with_child((
                         Mesh3d(parts.stitch.handle.clone()),
                         MeshMaterial3d(terrain_material_w.clone()),
+                        NotShadowCaster,
                         NoAutoAabb,
                         parts.stitch.aabb,
                     ));
@@ -384,15 +582,19 @@ fn init_grids(
         let mut trim = commands.spawn((
             Mesh3d(parts.trim.handle.clone()),
             MeshMaterial3d(terrain_material.clone()),
-            NotShadowCaster,
+            Pickable::IGNORE,
             Transform::from_xyz(-2.0 * square_width as f32, 0.0, -2.0 * square_width as f32),
             NoAutoAabb,
             parts.trim.aabb,
         ));
+        if !shadow_caster {
+            trim.insert(NotShadowCaster);
+        }
         if clipmap.wireframe {
             trim.with_child((
                 Mesh3d(parts.trim.handle.clone()),
                 MeshMaterial3d(terrain_material_w.clone()),
+                NotShadowCaster,
                 NoAutoAabb,
                 parts.trim.aabb,
             ));
@@ -402,6 +604,13 @@ fn init_grids(
     }
 }
 
+/// Clipmap components synced into the grid materials every frame.
+type GridUpdateData = (
+    &'static Clipmap,
+    Option<&'static ClipmapLevels>,
+    Option<Ref<'static, ClipmapMaterialRules>>,
+);
+
 fn update_grids(
     mut transforms: Query<&mut Transform>,
     mut aabbs: Query<&mut Aabb>,
@@ -409,12 +618,12 @@ fn update_grids(
     terrain_material_handles: Query<
         &MeshMaterial3d<ExtendedMaterial<StandardMaterial, GridMaterial>>,
     >,
-    clipmaps: Query<&Clipmap>,
+    clipmaps: Query<GridUpdateData>,
     children: Query<&Children>,
     grids: Query<(Entity, &ClipmapGrid, &ChildOf), With<Transform>>,
 ) {
     for (entity, grid, clipmap) in grids {
-        let clipmap = clipmaps.get(clipmap.parent()).unwrap();
+        let (clipmap, levels, rules) = clipmaps.get(clipmap.parent()).unwrap();
         let filler_width = 2 - clipmap.half_width as i32 % 2;
         let snap_scale = grid.scale(clipmap.base_scale) * filler_width as f32;
         let target_pos = transforms.get(clipmap.target).unwrap().translation;
@@ -442,7 +651,7 @@ fn update_grids(
         });
 
         let grid_pos = (snap_pos.extend(0.0).xzy() + trim_transform.translation * snap_scale).xz();
-        let aabb_scale = 2u32.pow(1 + grid.level) as f32;
+        let aabb_scale = 2.0 * grid.scale(clipmap.base_scale);
         for child in children.iter_descendants(entity) {
             let Ok(material) = terrain_material_handles.get(child) else {
                 continue;
@@ -454,28 +663,66 @@ fn update_grids(
                 continue;
             };
             material.extension.translation = grid_pos;
+            material.extension.minmax = Vec2::new(clipmap.min, clipmap.max);
+            material.extension.target = target_pos.xz();
+            material.extension.horizon_ao =
+                clipmap.horizon.as_ref().map_or(0.0, |horizon| horizon.ao);
+            material.extension.horizon_shadow = GpuHorizonShadow::new(clipmap.horizon.as_ref());
+            if let Some(levels) = levels {
+                material.extension.levels_size = levels.size;
+            }
+            if let Some(rules) = rules.as_ref().filter(|rules| rules.is_changed()) {
+                material.extension.rules = GpuMaterialRules::from(&**rules);
+            }
             aabb.center.y = (clipmap.max + clipmap.min) / aabb_scale;
             aabb.half_extents.y = (clipmap.max - clipmap.min) / aabb_scale;
         }
     }
 }
 
+/// Syncs the directional lights with a [`HorizonShadow`] into the grid materials.
+fn update_grid_lights(
+    mut materials: ResMut<Assets<ExtendedMaterial<StandardMaterial, GridMaterial>>>,
+    horizon_lights: Res<HorizonLights>,
+) {
+    // New grids start with the current lights.
+    if !horizon_lights.is_changed() {
+        return;
+    }
+    for (_, material) in materials.iter_mut() {
+        material.extension.horizon_lights = horizon_lights.0.clone();
+    }
+}
+
 #[repr(C)]
 #[derive(Eq, PartialEq, Hash, Copy, Clone)]
-struct WireframeKey {
+struct GridMaterialKey {
     wireframe: bool,
+    levels: bool,
+    layers: bool,
+    rules: bool,
+    triplanar: bool,
+    horizon: Option<HorizonEncoding>,
 }
 
-impl From<&GridMaterial> for WireframeKey {
+impl From<&GridMaterial> for GridMaterialKey {
     fn from(material: &GridMaterial) -> Self {
         Self {
             wireframe: material.wireframe != 0,
+            levels: material.levels.is_some(),
+            layers: material.control.is_some(),
+            rules: material.rules.count > 0,
+            triplanar: material.triplanar,
+            horizon: material
+                .horizon
+                .is_some()
+                .then_some(material.horizon_encoding),
         }
     }
 }
 
 #[derive(Asset, AsBindGroup, Reflect, Debug, Clone)]
-#[bind_group_data(WireframeKey)]
+#[bind_group_data(GridMaterialKey)]
 struct GridMaterial {
     #[texture(100)]
     #[sampler(101)]
@@ -485,9 +732,10 @@ struct GridMaterial {
     heightmap: Handle<Image>,
     #[texture(104, dimension = "2d_array")]
     #[sampler(105)]
-    horizon: Handle<Image>,
+    horizon: Option<Handle<Image>>,
     #[uniform(106)]
     horizon_coeffs: u32,
+    horizon_encoding: HorizonEncoding,
     #[uniform(107)]
     lod: u32,
     #[uniform(108)]
@@ -498,6 +746,41 @@ struct GridMaterial {
     translation: Vec2,
     #[uniform(111)]
     wireframe: u32,
+    #[uniform(112)]
+    base_scale: f32,
+    #[uniform(113)]
+    half_width: u32,
+    #[uniform(114)]
+    morph_width: f32,
+    #[uniform(115)]
+    target: Vec2,
+    #[texture(116, dimension = "2d_array")]
+    levels: Option<Handle<Image>>,
+    #[uniform(117)]
+    levels_size: UVec2,
+    #[texture(118, dimension = "2d_array")]
+    #[sampler(119)]
+    control: Option<Handle<Image>>,
+    #[texture(120, dimension = "2d_array")]
+    #[sampler(121)]
+    layer_albedo: Option<Handle<Image>>,
+    #[texture(122, dimension = "2d_array")]
+    layer_normal: Option<Handle<Image>>,
+    #[texture(123, dimension = "2d_array")]
+    layer_roughness: Option<Handle<Image>>,
+    #[uniform(124)]
+    layer_tiling: [Vec4; MAX_LAYERS / 4],
+    #[uniform(125)]
+    layer_count: u32,
+    #[uniform(126)]
+    rules: GpuMaterialRules,
+    #[uniform(127)]
+    horizon_ao: f32,
+    #[uniform(128)]
+    horizon_shadow: GpuHorizonShadow,
+    #[uniform(129)]
+    horizon_lights: GpuHorizonLights,
+    triplanar: bool,
 }
 
 impl MaterialExtension for GridMaterial {
@@ -507,6 +790,12 @@ impl MaterialExtension for GridMaterial {
         )
     }
 
+    fn prepass_vertex_shader() -> ShaderRef {
+        ShaderRef::Path(
+            AssetPath::from_path_buf(embedded_path!("terrain.wgsl")).with_source("embedded"),
+        )
+    }
+
     fn deferred_vertex_shader() -> ShaderRef {
         ShaderRef::Path(
             AssetPath::from_path_buf(embedded_path!("terrain.wgsl")).with_source("embedded"),
@@ -531,6 +820,30 @@ impl MaterialExtension for GridMaterial {
         _: &bevy::mesh::MeshVertexBufferLayoutRef,
         key: bevy::pbr::MaterialExtensionKey<Self>,
     ) -> std::result::Result<(), bevy::render::render_resource::SpecializedMeshPipelineError> {
+        if key.bind_group_data.levels {
+            descriptor.vertex.shader_defs.push("CLIPMAP_LEVELS".into());
+            if let Some(fragment) = descriptor.fragment.as_mut() {
+                fragment.shader_defs.push("CLIPMAP_LEVELS".into());
+            }
+        }
+        if key.bind_group_data.layers
+            && let Some(fragment) = descriptor.fragment.as_mut()
+        {
+            fragment.shader_defs.push("CLIPMAP_LAYERS".into());
+            if key.bind_group_data.rules {
+                fragment.shader_defs.push("CLIPMAP_RULES".into());
+            }
+            if key.bind_group_data.triplanar {
+                fragment.shader_defs.push("CLIPMAP_TRIPLANAR".into());
+            }
+        }
+        if let Some(encoding) = key.bind_group_data.horizon
+            && let Some(fragment) = descriptor.fragment.as_mut()
+        {
+            fragment
+                .shader_defs
+                .extend(encoding.shader_defs().iter().map(|&def| def.into()));
+        }
         if key.bind_group_data.wireframe {
             descriptor.primitive.polygon_mode = bevy::render::render_resource::PolygonMode::Line;
             descriptor.depth_stencil.as_mut().unwrap().bias.slope_scale = 1.0;
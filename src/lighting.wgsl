@@ -0,0 +1,557 @@
+#define_import_path bevy_clipmap::lighting
+
+// `bevy_pbr::pbr_functions::apply_pbr_lighting` with the horizon map shadowing the direct light
+// and occluding the indirect light.
+
+#import bevy_pbr::{
+    pbr_types,
+    mesh_view_bindings as view_bindings,
+    mesh_view_types,
+    lighting,
+    lighting::{LAYER_BASE, LAYER_CLEARCOAT},
+    transmission,
+    clustered_forward as clustering,
+    shadows,
+    ambient,
+    irradiance_volume,
+    mesh_types::{MESH_FLAGS_SHADOW_RECEIVER_BIT, MESH_FLAGS_TRANSMITTED_SHADOW_RECEIVER_BIT},
+}
+#import bevy_render::maths::E
+#import bevy_clipmap::horizon
+
+#ifdef ENVIRONMENT_MAP
+#import bevy_pbr::environment_map
+#endif
+
+fn calculate_diffuse_color(
+    base_color: vec3<f32>,
+    metallic: f32,
+    specular_transmission: f32,
+    diffuse_transmission: f32
+) -> vec3<f32> {
+    return base_color * (1.0 - metallic) * (1.0 - specular_transmission) *
+        (1.0 - diffuse_transmission);
+}
+
+fn calculate_F0(base_color: vec3<f32>, metallic: f32, reflectance: vec3<f32>) -> vec3<f32> {
+    return 0.16 * reflectance * reflectance * (1.0 - metallic) + base_color * metallic;
+}
+
+fn apply_pbr_lighting(
+    in: pbr_types::PbrInput,
+    horizon_uv: vec2<f32>,
+    horizon_height_range: f32,
+) -> vec4<f32> {
+    var output_color: vec4<f32> = in.material.base_color;
+
+    let emissive = in.material.emissive;
+
+    // calculate non-linear roughness from linear perceptualRoughness
+    let metallic = in.material.metallic;
+    let perceptual_roughness = in.material.perceptual_roughness;
+    let roughness = lighting::perceptualRoughnessToRoughness(perceptual_roughness);
+    let ior = in.material.ior;
+    let thickness = in.material.thickness;
+    let reflectance = in.material.reflectance;
+    let diffuse_transmission = in.material.diffuse_transmission;
+    let specular_transmission = in.material.specular_transmission;
+
+    let specular_transmissive_color = specular_transmission * in.material.base_color.rgb;
+
+    // Sky visibility from the horizon map occludes the indirect light.
+    var horizon_occlusion = 1.0;
+#ifdef CLIPMAP_HORIZON
+    if horizon::horizon_ao > 0.0 {
+        let visibility = horizon::horizon_visibility(horizon_uv, horizon_height_range);
+        horizon_occlusion = mix(1.0, visibility, saturate(horizon::horizon_ao));
+    }
+#endif
+    let diffuse_occlusion = in.diffuse_occlusion * horizon_occlusion;
+    let specular_occlusion = in.specular_occlusion * horizon_occlusion;
+
+    // Neubelt and Pettineo 2013, "Crafting a Next-gen Material Pipeline for The Order: 1886"
+    let NdotV = max(dot(in.N, in.V), 0.0001);
+    let R = reflect(-in.V, in.N);
+
+#ifdef STANDARD_MATERIAL_CLEARCOAT
+    // Do the above calculations again for the clearcoat layer. Remember that
+    // the clearcoat can have its own roughness and its own normal.
+    let clearcoat = in.material.clearcoat;
+    let clearcoat_perceptual_roughness = in.material.clearcoat_perceptual_roughness;
+    let clearcoat_roughness = lighting::perceptualRoughnessToRoughness(clearcoat_perceptual_roughness);
+    let clearcoat_N = in.clearcoat_N;
+    let clearcoat_NdotV = max(dot(clearcoat_N, in.V), 0.0001);
+    let clearcoat_R = reflect(-in.V, clearcoat_N);
+#endif  // STANDARD_MATERIAL_CLEARCOAT
+
+    let diffuse_color = calculate_diffuse_color(
+        output_color.rgb,
+        metallic,
+        specular_transmission,
+        diffuse_transmission
+    );
+
+    // Diffuse transmissive strength is inversely related to metallicity and specular transmission, but directly related to diffuse transmission
+    let diffuse_transmissive_color = output_color.rgb * (1.0 - metallic) * (1.0 - specular_transmission) * diffuse_transmission;
+
+    // Calculate the world position of the second Lambertian lobe used for diffuse transmission, by subtracting material thickness
+    let diffuse_transmissive_lobe_world_position = in.world_position - vec4<f32>(in.world_normal, 0.0) * thickness;
+
+    let F0 = calculate_F0(output_color.rgb, metallic, reflectance);
+    let F_ab = lighting::F_AB(perceptual_roughness, NdotV);
+
+    var direct_light: vec3<f32> = vec3<f32>(0.0);
+
+    // Transmitted Light (Specular and Diffuse)
+    var transmitted_light: vec3<f32> = vec3<f32>(0.0);
+
+    // Pack all the values into a structure.
+    var lighting_input: lighting::LightingInput;
+    lighting_input.layers[LAYER_BASE].NdotV = NdotV;
+    lighting_input.layers[LAYER_BASE].N = in.N;
+    lighting_input.layers[LAYER_BASE].R = R;
+    lighting_input.layers[LAYER_BASE].perceptual_roughness = perceptual_roughness;
+    lighting_input.layers[LAYER_BASE].roughness = roughness;
+    lighting_input.P = in.world_position.xyz;
+    lighting_input.V = in.V;
+    lighting_input.diffuse_color = diffuse_color;
+    lighting_input.F0_ = F0;
+    lighting_input.F_ab = F_ab;
+#ifdef STANDARD_MATERIAL_CLEARCOAT
+    lighting_input.layers[LAYER_CLEARCOAT].NdotV = clearcoat_NdotV;
+    lighting_input.layers[LAYER_CLEARCOAT].N = clearcoat_N;
+    lighting_input.layers[LAYER_CLEARCOAT].R = clearcoat_R;
+    lighting_input.layers[LAYER_CLEARCOAT].perceptual_roughness = clearcoat_perceptual_roughness;
+    lighting_input.layers[LAYER_CLEARCOAT].roughness = clearcoat_roughness;
+    lighting_input.clearcoat_strength = clearcoat;
+#endif  // STANDARD_MATERIAL_CLEARCOAT
+#ifdef STANDARD_MATERIAL_ANISOTROPY
+    lighting_input.anisotropy = in.anisotropy_strength;
+    lighting_input.Ta = in.anisotropy_T;
+    lighting_input.Ba = in.anisotropy_B;
+#endif  // STANDARD_MATERIAL_ANISOTROPY
+
+    // And do the same for transmissive if we need to.
+#ifdef STANDARD_MATERIAL_DIFFUSE_TRANSMISSION
+    var transmissive_lighting_input: lighting::LightingInput;
+    transmissive_lighting_input.layers[LAYER_BASE].NdotV = 1.0;
+    transmissive_lighting_input.layers[LAYER_BASE].N = -in.N;
+    transmissive_lighting_input.layers[LAYER_BASE].R = vec3(0.0);
+    transmissive_lighting_input.layers[LAYER_BASE].perceptual_roughness = 1.0;
+    transmissive_lighting_input.layers[LAYER_BASE].roughness = 1.0;
+    transmissive_lighting_input.P = diffuse_transmissive_lobe_world_position.xyz;
+    transmissive_lighting_input.V = -in.V;
+    transmissive_lighting_input.diffuse_color = diffuse_transmissive_color;
+    transmissive_lighting_input.F0_ = vec3(0.0);
+    transmissive_lighting_input.F_ab = vec2(0.1);
+#ifdef STANDARD_MATERIAL_CLEARCOAT
+    transmissive_lighting_input.layers[LAYER_CLEARCOAT].NdotV = 0.0;
+    transmissive_lighting_input.layers[LAYER_CLEARCOAT].N = vec3(0.0);
+    transmissive_lighting_input.layers[LAYER_CLEARCOAT].R = vec3(0.0);
+    transmissive_lighting_input.layers[LAYER_CLEARCOAT].perceptual_roughness = 0.0;
+    transmissive_lighting_input.layers[LAYER_CLEARCOAT].roughness = 0.0;
+    transmissive_lighting_input.clearcoat_strength = 0.0;
+#endif  // STANDARD_MATERIAL_CLEARCOAT
+#ifdef STANDARD_MATERIAL_ANISOTROPY
+    transmissive_lighting_input.anisotropy = in.anisotropy_strength;
+    transmissive_lighting_input.Ta = in.anisotropy_T;
+    transmissive_lighting_input.Ba = in.anisotropy_B;
+#endif  // STANDARD_MATERIAL_ANISOTROPY
+#endif  // STANDARD_MATERIAL_DIFFUSE_TRANSMISSION
+
+    let view_z = dot(vec4<f32>(
+        view_bindings::view.view_from_world[0].z,
+        view_bindings::view.view_from_world[1].z,
+        view_bindings::view.view_from_world[2].z,
+        view_bindings::view.view_from_world[3].z
+    ), in.world_position);
+    let cluster_index = clustering::fragment_cluster_index(in.frag_coord.xy, view_z, in.is_orthographic);
+    var clusterable_object_index_ranges =
+        clustering::unpack_clusterable_object_index_ranges(cluster_index);
+
+    // Point lights (direct)
+    for (var i: u32 = clusterable_object_index_ranges.first_point_light_index_offset;
+            i < clusterable_object_index_ranges.first_spot_light_index_offset;
+            i = i + 1u) {
+        let light_id = clustering::get_clusterable_object_id(i);
+
+        // If we're lightmapped, disable diffuse contribution from the light if
+        // requested, to avoid double-counting light.
+#ifdef LIGHTMAP
+        let enable_diffuse =
+            (view_bindings::clusterable_objects.data[light_id].flags &
+                mesh_view_types::POINT_LIGHT_FLAGS_AFFECTS_LIGHTMAPPED_MESH_DIFFUSE_BIT) != 0u;
+#else   // LIGHTMAP
+        let enable_diffuse = true;
+#endif  // LIGHTMAP
+
+        var shadow: f32 = 1.0;
+        if ((in.flags & MESH_FLAGS_SHADOW_RECEIVER_BIT) != 0u
+                && (view_bindings::clusterable_objects.data[light_id].flags & mesh_view_types::POINT_LIGHT_FLAGS_SHADOWS_ENABLED_BIT) != 0u) {
+            shadow = shadows::fetch_point_shadow(light_id, in.world_position, in.world_normal);
+        }
+
+#ifdef CLIPMAP_HORIZON
+        // Occluded when the light is below the horizon toward it.
+        let horizon_shadow = horizon::horizon_local_shadow(
+            horizon_uv,
+            horizon_height_range,
+            view_bindings::clusterable_objects.data[light_id].position_radius.xyz - in.world_position.xyz,
+        );
+#else
+        let horizon_shadow = 1.0;
+#endif
+
+        let light_contrib = lighting::point_light(light_id, &lighting_input, enable_diffuse, true);
+        direct_light += light_contrib * min(shadow, horizon_shadow);
+
+#ifdef STANDARD_MATERIAL_DIFFUSE_TRANSMISSION
+        // NOTE: We use the diffuse transmissive color, the second Lambertian lobe's calculated
+        // world position, inverted normal and view vectors, and the following simplified
+        // values for a fully diffuse transmitted light contribution approximation:
+        //
+        // roughness = 1.0;
+        // NdotV = 1.0;
+        // R = vec3<f32>(0.0) // doesn't really matter
+        // F_ab = vec2<f32>(0.1)
+        // F0 = vec3<f32>(0.0)
+        var transmitted_shadow: f32 = 1.0;
+        if ((in.flags & (MESH_FLAGS_SHADOW_RECEIVER_BIT | MESH_FLAGS_TRANSMITTED_SHADOW_RECEIVER_BIT)) == (MESH_FLAGS_SHADOW_RECEIVER_BIT | MESH_FLAGS_TRANSMITTED_SHADOW_RECEIVER_BIT)
+                && (view_bindings::clusterable_objects.data[light_id].flags & mesh_view_types::POINT_LIGHT_FLAGS_SHADOWS_ENABLED_BIT) != 0u) {
+            transmitted_shadow = shadows::fetch_point_shadow(light_id, diffuse_transmissive_lobe_world_position, -in.world_normal);
+        }
+
+        let transmitted_light_contrib =
+            lighting::point_light(light_id, &transmissive_lighting_input, enable_diffuse, true);
+        transmitted_light += transmitted_light_contrib * transmitted_shadow;
+#endif
+    }
+
+    // Spot lights (direct)
+    for (var i: u32 = clusterable_object_index_ranges.first_spot_light_index_offset;
+            i < clusterable_object_index_ranges.first_reflection_probe_index_offset;
+            i = i + 1u) {
+        let light_id = clustering::get_clusterable_object_id(i);
+
+        // If we're lightmapped, disable diffuse contribution from the light if
+        // requested, to avoid double-counting light.
+#ifdef LIGHTMAP
+        let enable_diffuse =
+            (view_bindings::clusterable_objects.data[light_id].flags &
+                mesh_view_types::POINT_LIGHT_FLAGS_AFFECTS_LIGHTMAPPED_MESH_DIFFUSE_BIT) != 0u;
+#else   // LIGHTMAP
+        let enable_diffuse = true;
+#endif  // LIGHTMAP
+
+        var shadow: f32 = 1.0;
+        if ((in.flags & MESH_FLAGS_SHADOW_RECEIVER_BIT) != 0u
+                && (view_bindings::clusterable_objects.data[light_id].flags &
+                    mesh_view_types::POINT_LIGHT_FLAGS_SHADOWS_ENABLED_BIT) != 0u) {
+            shadow = shadows::fetch_spot_shadow(
+                light_id,
+                in.world_position,
+                in.world_normal,
+                view_bindings::clusterable_objects.data[light_id].shadow_map_near_z,
+            );
+        }
+
+#ifdef CLIPMAP_HORIZON
+        let horizon_shadow = horizon::horizon_local_shadow(
+            horizon_uv,
+            horizon_height_range,
+            view_bindings::clusterable_objects.data[light_id].position_radius.xyz - in.world_position.xyz,
+        );
+#else
+        let horizon_shadow = 1.0;
+#endif
+
+        let light_contrib = lighting::spot_light(light_id, &lighting_input, enable_diffuse);
+        direct_light += light_contrib * min(shadow, horizon_shadow);
+
+#ifdef STANDARD_MATERIAL_DIFFUSE_TRANSMISSION
+        // NOTE: We use the diffuse transmissive color, the second Lambertian lobe's calculated
+        // world position, inverted normal and view vectors, and the following simplified
+        // values for a fully diffuse transmitted light contribution approximation:
+        //
+        // roughness = 1.0;
+        // NdotV = 1.0;
+        // R = vec3<f32>(0.0) // doesn't really matter
+        // F_ab = vec2<f32>(0.1)
+        // F0 = vec3<f32>(0.0)
+        var transmitted_shadow: f32 = 1.0;
+        if ((in.flags & (MESH_FLAGS_SHADOW_RECEIVER_BIT | MESH_FLAGS_TRANSMITTED_SHADOW_RECEIVER_BIT)) == (MESH_FLAGS_SHADOW_RECEIVER_BIT | MESH_FLAGS_TRANSMITTED_SHADOW_RECEIVER_BIT)
+                && (view_bindings::clusterable_objects.data[light_id].flags & mesh_view_types::POINT_LIGHT_FLAGS_SHADOWS_ENABLED_BIT) != 0u) {
+            transmitted_shadow = shadows::fetch_spot_shadow(
+                light_id,
+                diffuse_transmissive_lobe_world_position,
+                -in.world_normal,
+                view_bindings::clusterable_objects.data[light_id].shadow_map_near_z,
+            );
+        }
+
+        let transmitted_light_contrib =
+            lighting::spot_light(light_id, &transmissive_lighting_input, enable_diffuse);
+        transmitted_light += transmitted_light_contrib * transmitted_shadow;
+#endif
+    }
+
+    // directional lights (direct)
+    let n_directional_lights = view_bindings::lights.n_directional_lights;
+    for (var i: u32 = 0u; i < n_directional_lights; i = i + 1u) {
+        // check if this light should be skipped, which occurs if this light does not intersect with the view
+        // note point and spot lights aren't skippable, as the relevant lights are filtered in `assign_lights_to_clusters`
+        let light = &view_bindings::lights.directional_lights[i];
+
+        // If we're lightmapped, disable diffuse contribution from the light if
+        // requested, to avoid double-counting light.
+#ifdef LIGHTMAP
+        let enable_diffuse =
+            ((*light).flags &
+                mesh_view_types::DIRECTIONAL_LIGHT_FLAGS_AFFECTS_LIGHTMAPPED_MESH_DIFFUSE_BIT) !=
+                0u;
+#else   // LIGHTMAP
+        let enable_diffuse = true;
+#endif  // LIGHTMAP
+
+        var shadow: f32 = 1.0;
+        if ((in.flags & MESH_FLAGS_SHADOW_RECEIVER_BIT) != 0u
+                && (view_bindings::lights.directional_lights[i].flags & mesh_view_types::DIRECTIONAL_LIGHT_FLAGS_SHADOWS_ENABLED_BIT) != 0u) {
+            shadow = shadows::fetch_directional_shadow(i, in.world_position, in.world_normal, view_z);
+        }
+
+#ifdef CLIPMAP_HORIZON
+        let horizon_shadow = horizon::horizon_shadow(
+            horizon_uv,
+            horizon_height_range,
+            (*light).direction_to_light,
+        );
+#else
+        let horizon_shadow = 1.0;
+#endif
+
+        var light_contrib = lighting::directional_light(i, &lighting_input, enable_diffuse);
+
+#ifdef DIRECTIONAL_LIGHT_SHADOW_MAP_DEBUG_CASCADES
+        light_contrib = shadows::cascade_debug_visualization(light_contrib, i, view_z);
+#endif
+        direct_light += light_contrib * min(shadow, horizon_shadow);
+
+#ifdef STANDARD_MATERIAL_DIFFUSE_TRANSMISSION
+        // NOTE: We use the diffuse transmissive color, the second Lambertian lobe's calculated
+        // world position, inverted normal and view vectors, and the following simplified
+        // values for a fully diffuse transmitted light contribution approximation:
+        //
+        // roughness = 1.0;
+        // NdotV = 1.0;
+        // R = vec3<f32>(0.0) // doesn't really matter
+        // F_ab = vec2<f32>(0.1)
+        // F0 = vec3<f32>(0.0)
+        var transmitted_shadow: f32 = 1.0;
+        if ((in.flags & (MESH_FLAGS_SHADOW_RECEIVER_BIT | MESH_FLAGS_TRANSMITTED_SHADOW_RECEIVER_BIT)) == (MESH_FLAGS_SHADOW_RECEIVER_BIT | MESH_FLAGS_TRANSMITTED_SHADOW_RECEIVER_BIT)
+                && (view_bindings::lights.directional_lights[i].flags & mesh_view_types::DIRECTIONAL_LIGHT_FLAGS_SHADOWS_ENABLED_BIT) != 0u) {
+            transmitted_shadow = shadows::fetch_directional_shadow(i, diffuse_transmissive_lobe_world_position, -in.world_normal, view_z);
+        }
+
+        let transmitted_light_contrib =
+            lighting::directional_light(i, &transmissive_lighting_input, enable_diffuse);
+        transmitted_light += transmitted_light_contrib * transmitted_shadow;
+#endif
+    }
+
+#ifdef STANDARD_MATERIAL_DIFFUSE_TRANSMISSION
+    // NOTE: We use the diffuse transmissive color, the second Lambertian lobe's calculated
+    // world position, inverted normal and view vectors, and the following simplified
+    // values for a fully diffuse transmitted light contribution approximation:
+    //
+    // perceptual_roughness = 1.0;
+    // NdotV = 1.0;
+    // F0 = vec3<f32>(0.0)
+    // diffuse_occlusion = vec3<f32>(1.0)
+    transmitted_light += ambient::ambient_light(diffuse_transmissive_lobe_world_position, -in.N, -in.V, 1.0, diffuse_transmissive_color, vec3<f32>(0.0), 1.0, vec3<f32>(1.0));
+#endif
+
+    // Diffuse indirect lighting can come from a variety of sources. The
+    // priority goes like this:
+    //
+    // 1. Lightmap (highest)
+    // 2. Irradiance volume
+    // 3. Environment map (lowest)
+    //
+    // When we find a source of diffuse indirect lighting, we stop accumulating
+    // any more diffuse indirect light. This avoids double-counting if, for
+    // example, both lightmaps and irradiance volumes are present.
+
+    var indirect_light = vec3(0.0f);
+    var found_diffuse_indirect = false;
+
+#ifdef LIGHTMAP
+    indirect_light += in.lightmap_light * diffuse_color;
+    found_diffuse_indirect = true;
+#endif
+
+#ifdef IRRADIANCE_VOLUME
+    // Irradiance volume light (indirect)
+    if (!found_diffuse_indirect) {
+        let irradiance_volume_light = irradiance_volume::irradiance_volume_light(
+            in.world_position.xyz,
+            in.N,
+            &clusterable_object_index_ranges,
+        );
+        indirect_light += irradiance_volume_light * diffuse_color * diffuse_occlusion;
+        found_diffuse_indirect = true;
+    }
+#endif
+
+    // Environment map light (indirect)
+#ifdef ENVIRONMENT_MAP
+    // If screen space reflections are going to be used for this material, don't
+    // accumulate environment map light yet. The SSR shader will do it.
+#ifdef SCREEN_SPACE_REFLECTIONS
+    let use_ssr = perceptual_roughness <=
+        view_bindings::ssr_settings.perceptual_roughness_threshold;
+#else   // SCREEN_SPACE_REFLECTIONS
+    let use_ssr = false;
+#endif  // SCREEN_SPACE_REFLECTIONS
+
+    if (!use_ssr) {
+#ifdef STANDARD_MATERIAL_ANISOTROPY
+        var bent_normal_lighting_input = lighting_input;
+        lighting::bend_normal_for_anisotropy(&bent_normal_lighting_input);
+        let environment_map_lighting_input = &bent_normal_lighting_input;
+#else   // STANDARD_MATERIAL_ANISOTROPY
+        let environment_map_lighting_input = &lighting_input;
+#endif  // STANDARD_MATERIAL_ANISOTROPY
+
+        let environment_light = environment_map::environment_map_light(
+            environment_map_lighting_input,
+            &clusterable_object_index_ranges,
+            found_diffuse_indirect,
+        );
+
+        indirect_light += environment_light.diffuse * diffuse_occlusion +
+            environment_light.specular * specular_occlusion;
+    }
+#endif  // ENVIRONMENT_MAP
+
+    // Ambient light (indirect)
+    // If we are lightmapped, disable the ambient contribution if requested.
+    // This is to avoid double-counting ambient light. (It might be part of the lightmap)
+#ifdef LIGHTMAP
+    let enable_ambient = view_bindings::lights.ambient_light_affects_lightmapped_meshes != 0u;
+#else   // LIGHTMAP
+    let enable_ambient = true;
+#endif  // LIGHTMAP
+    if (enable_ambient) {
+        indirect_light += ambient::ambient_light(in.world_position, in.N, in.V, NdotV, diffuse_color, F0, perceptual_roughness, diffuse_occlusion);
+    }
+
+    // we'll use the specular component of the transmitted environment
+    // light in the call to `specular_transmissive_light()` below
+    var specular_transmitted_environment_light = vec3<f32>(0.0);
+
+#ifdef ENVIRONMENT_MAP
+
+#ifdef STANDARD_MATERIAL_DIFFUSE_OR_SPECULAR_TRANSMISSION
+    // NOTE: We use the diffuse transmissive color, inverted normal and view vectors,
+    // and the following simplified values for the transmitted environment light contribution
+    // approximation:
+    //
+    // diffuse_color = vec3<f32>(1.0) // later we use `diffuse_transmissive_color` and `specular_transmissive_color`
+    // NdotV = 1.0;
+    // R = T // see definition below
+    // F0 = vec3<f32>(1.0)
+    // diffuse_occlusion = 1.0
+    //
+    // (This one is slightly different from the other light types above, because the environment
+    // map light returns both diffuse and specular components separately, and we want to use both)
+
+    let T = -normalize(
+        in.V + // start with view vector at entry point
+        refract(in.V, -in.N, 1.0 / ior) * thickness // add refracted vector scaled by thickness, towards exit point
+    ); // normalize to find exit point view vector
+
+    var transmissive_environment_light_input: lighting::LightingInput;
+    transmissive_environment_light_input.diffuse_color = vec3(1.0);
+    transmissive_environment_light_input.layers[LAYER_BASE].NdotV = 1.0;
+    transmissive_environment_light_input.P = in.world_position.xyz;
+    transmissive_environment_light_input.layers[LAYER_BASE].N = -in.N;
+    transmissive_environment_light_input.V = in.V;
+    transmissive_environment_light_input.layers[LAYER_BASE].R = T;
+    transmissive_environment_light_input.layers[LAYER_BASE].perceptual_roughness = perceptual_roughness;
+    transmissive_environment_light_input.layers[LAYER_BASE].roughness = roughness;
+    transmissive_environment_light_input.F0_ = vec3<f32>(1.0);
+    transmissive_environment_light_input.F_ab = vec2(0.1);
+#ifdef STANDARD_MATERIAL_CLEARCOAT
+    // No clearcoat.
+    transmissive_environment_light_input.clearcoat_strength = 0.0;
+    transmissive_environment_light_input.layers[LAYER_CLEARCOAT].NdotV = 0.0;
+    transmissive_environment_light_input.layers[LAYER_CLEARCOAT].N = in.N;
+    transmissive_environment_light_input.layers[LAYER_CLEARCOAT].R = vec3(0.0);
+    transmissive_environment_light_input.layers[LAYER_CLEARCOAT].perceptual_roughness = 0.0;
+    transmissive_environment_light_input.layers[LAYER_CLEARCOAT].roughness = 0.0;
+#endif  // STANDARD_MATERIAL_CLEARCOAT
+
+    let transmitted_environment_light = environment_map::environment_map_light(
+        &transmissive_environment_light_input,
+        &clusterable_object_index_ranges,
+        false,
+    );
+
+#ifdef STANDARD_MATERIAL_DIFFUSE_TRANSMISSION
+    transmitted_light += transmitted_environment_light.diffuse * diffuse_transmissive_color;
+#endif  // STANDARD_MATERIAL_DIFFUSE_TRANSMISSION
+#ifdef STANDARD_MATERIAL_SPECULAR_TRANSMISSION
+    specular_transmitted_environment_light = transmitted_environment_light.specular * specular_transmissive_color;
+#endif  // STANDARD_MATERIAL_SPECULAR_TRANSMISSION
+
+#endif  // STANDARD_MATERIAL_SPECULAR_OR_DIFFUSE_TRANSMISSION
+
+#endif  // ENVIRONMENT_MAP
+
+    var emissive_light = emissive.rgb * output_color.a;
+
+    // "The clearcoat layer is on top of emission in the layering stack.
+    // Consequently, the emission is darkened by the Fresnel term."
+    //
+    // <https://github.com/KhronosGroup/glTF/blob/main/extensions/2.0/Khronos/KHR_materials_clearcoat/README.md#emission>
+#ifdef STANDARD_MATERIAL_CLEARCOAT
+    emissive_light = emissive_light * (0.04 + (1.0 - 0.04) * pow(1.0 - clearcoat_NdotV, 5.0));
+#endif
+
+    emissive_light = emissive_light * mix(1.0, view_bindings::view.exposure, emissive.a);
+
+#ifdef STANDARD_MATERIAL_SPECULAR_TRANSMISSION
+    transmitted_light += transmission::specular_transmissive_light(in.world_position, in.frag_coord.xyz, view_z, in.N, in.V, F0, ior, thickness, perceptual_roughness, specular_transmissive_color, specular_transmitted_environment_light).rgb;
+
+    if (in.material.flags & pbr_types::STANDARD_MATERIAL_FLAGS_ATTENUATION_ENABLED_BIT) != 0u {
+        // We reuse the `atmospheric_fog()` function here, as it's fundamentally
+        // equivalent to the attenuation that takes place inside the material volume,
+        // and will allow us to eventually hook up subsurface scattering more easily
+        var attenuation_fog: mesh_view_types::Fog;
+        attenuation_fog.base_color.a = 1.0;
+        attenuation_fog.be = pow(1.0 - in.material.attenuation_color.rgb, vec3<f32>(E)) / in.material.attenuation_distance;
+        // TODO: Add the subsurface scattering factor below
+        // attenuation_fog.bi = /* ... */
+        transmitted_light = bevy_pbr::fog::atmospheric_fog(
+            attenuation_fog, vec4<f32>(transmitted_light, 1.0), thickness,
+            vec3<f32>(0.0) // TODO: Pass in (pre-attenuated) scattered light contribution here
+        ).rgb;
+    }
+#endif
+
+    // Total light
+    output_color = vec4<f32>(
+        (view_bindings::view.exposure * (transmitted_light + direct_light + indirect_light)) + emissive_light,
+        output_color.a
+    );
+
+    output_color = clustering::cluster_debug_visualization(
+        output_color,
+        view_z,
+        in.is_orthographic,
+        clusterable_object_index_ranges,
+        cluster_index,
+    );
+
+    return output_color;
+}
@@ -0,0 +1,102 @@
+use bevy::{prelude::*, render::render_resource::TextureDataOrder};
+
+use crate::{
+    Clipmap,
+    height::{read_texel, texel_bytes, write_texel},
+};
+
+/// Generates mip chains for clipmap heightmaps loaded with a single level.
+/// Every grid level samples the mip matching its vertex spacing.
+pub(crate) fn generate_heightmap_mips(
+    mut images: ResMut<Assets<Image>>,
+    clipmaps: Query<&Clipmap>,
+) {
+    for clipmap in clipmaps {
+        let Some(image) = images.get(&clipmap.heightmap) else {
+            continue;
+        };
+        if image.texture_descriptor.mip_level_count > 1
+            || image.data.is_none()
+            || texel_bytes(image.texture_descriptor.format).is_none()
+        {
+            continue;
+        }
+        build_mips(images.get_mut(&clipmap.heightmap).unwrap());
+    }
+}
+
+/// Number of mips in the full chain for the given size.
+pub(crate) fn mip_count(size: UVec2) -> u32 {
+    32 - size.max_element().max(1).leading_zeros()
+}
+
+/// Size of the mip level.
+pub(crate) fn mip_size(size: UVec2, mip: u32) -> UVec2 {
+    (size >> mip).max(UVec2::ONE)
+}
+
+/// Byte offset of the mip level in the image data.
+pub(crate) fn mip_offset(size: UVec2, mip: u32, texel_bytes: usize) -> usize {
+    (0..mip)
+        .map(|mip| mip_size(size, mip).element_product() as usize * texel_bytes)
+        .sum()
+}
+
+/// Byte offset of the mip level of the array layer in the image data, for either data order.
+pub(crate) fn subresource_offset(image: &Image, layer: u32, mip: u32, texel_bytes: usize) -> usize {
+    let size = image.size();
+    match image.data_order {
+        TextureDataOrder::LayerMajor => {
+            let mips = image.texture_descriptor.mip_level_count;
+            layer as usize * mip_offset(size, mips, texel_bytes)
+                + mip_offset(size, mip, texel_bytes)
+        }
+        TextureDataOrder::MipMajor => {
+            let layers = image.texture_descriptor.array_layer_count() as usize;
+            let mip_bytes = mip_size(size, mip).element_product() as usize * texel_bytes;
+            layers * mip_offset(size, mip, texel_bytes) + layer as usize * mip_bytes
+        }
+    }
+}
+
+/// Box-filters the rectangle of the mip level from the previous level.
+/// `min` and `max` are in texels of the level being written, `max` is exclusive.
+pub(crate) fn downsample_region(image: &mut Image, mip: u32, min: UVec2, max: UVec2) {
+    let format = image.texture_descriptor.format;
+    let bytes = texel_bytes(format).unwrap();
+    let size = image.size();
+    let src_size = mip_size(size, mip - 1);
+    let dst_size = mip_size(size, mip);
+    let src_offset = mip_offset(size, mip - 1, bytes);
+    let dst_offset = mip_offset(size, mip, bytes);
+    let data = image.data.as_mut().unwrap();
+
+    for y in min.y..max.y.min(dst_size.y) {
+        for x in min.x..max.x.min(dst_size.x) {
+            let mut sum = 0.0;
+            for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                let sx = (x * 2 + dx).min(src_size.x - 1) as usize;
+                let sy = (y * 2 + dy).min(src_size.y - 1) as usize;
+                let i = src_offset + (sy * src_size.x as usize + sx) * bytes;
+                sum += read_texel(format, &data[i..i + bytes]).unwrap();
+            }
+            let i = dst_offset + (y as usize * dst_size.x as usize + x as usize) * bytes;
+            write_texel(format, sum / 4.0, &mut data[i..i + bytes]);
+        }
+    }
+}
+
+/// Replaces the mips of the image with a full box-filtered chain built from level 0.
+pub(crate) fn build_mips(image: &mut Image) {
+    let size = image.size();
+    let bytes = texel_bytes(image.texture_descriptor.format).unwrap();
+    let mips = mip_count(size);
+
+    let data = image.data.as_mut().unwrap();
+    data.resize(mip_offset(size, mips, bytes), 0);
+    image.texture_descriptor.mip_level_count = mips;
+
+    for mip in 1..mips {
+        downsample_region(image, mip, UVec2::ZERO, mip_size(size, mip));
+    }
+}
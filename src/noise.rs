@@ -0,0 +1,136 @@
+use std::f32::consts::{SQRT_2, TAU};
+
+use bevy::prelude::*;
+
+use crate::source::{HeightSource, HeightSourceContext};
+
+/// How the octaves of [`NoiseSource`] are combined.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NoiseKind {
+    /// Fractal Brownian motion, rolling hills.
+    #[default]
+    Fbm,
+
+    /// Inverted absolute octaves, sharp mountain ridges.
+    Ridged,
+}
+
+/// Procedural heights from fractal gradient noise, no textures needed.
+#[derive(Clone, Debug)]
+pub struct NoiseSource {
+    /// Noise seed.
+    pub seed: u32,
+
+    /// Size of the terrain in level 0 texels.
+    pub size: UVec2,
+
+    /// Number of levels to provide.
+    pub levels: u32,
+
+    /// Wavelength of the first octave in level 0 texels.
+    pub wavelength: f32,
+
+    /// Number of octaves.
+    pub octaves: u32,
+
+    /// Frequency multiplier of every next octave.
+    pub lacunarity: f32,
+
+    /// Amplitude multiplier of every next octave.
+    pub gain: f32,
+
+    /// How the octaves are combined.
+    pub kind: NoiseKind,
+}
+
+impl Default for NoiseSource {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            size: UVec2::splat(65536),
+            levels: 7,
+            wavelength: 2048.0,
+            octaves: 8,
+            lacunarity: 2.0,
+            gain: 0.5,
+            kind: NoiseKind::Fbm,
+        }
+    }
+}
+
+impl NoiseSource {
+    /// Normalized height at the position given in level 0 texels.
+    pub fn height(&self, p: Vec2) -> f32 {
+        let mut p = p / self.wavelength;
+        let mut amplitude = 1.0;
+        let mut sum = 0.0;
+        let mut total = 0.0;
+        for octave in 0..self.octaves {
+            let n = gradient_noise(p, self.seed.wrapping_add(octave));
+            sum += amplitude
+                * match self.kind {
+                    NoiseKind::Fbm => n * 0.5 + 0.5,
+                    NoiseKind::Ridged => (1.0 - n.abs()).powi(2),
+                };
+            total += amplitude;
+            amplitude *= self.gain;
+            p *= self.lacunarity;
+        }
+        (sum / total).clamp(0.0, 1.0)
+    }
+}
+
+impl HeightSource for NoiseSource {
+    fn size(&self, _ctx: &HeightSourceContext) -> Option<UVec2> {
+        Some(self.size)
+    }
+
+    fn levels(&self) -> u32 {
+        self.levels
+    }
+
+    fn fill(
+        &mut self,
+        _ctx: &mut HeightSourceContext,
+        level: u32,
+        rect: IRect,
+    ) -> Option<Vec<f32>> {
+        let scale = 2u32.pow(level) as f32;
+        let heights = (rect.min.y..rect.max.y)
+            .flat_map(|y| (rect.min.x..rect.max.x).map(move |x| IVec2::new(x, y)))
+            // Center of the level 0 texels covered by the level texel.
+            .map(|texel| self.height((texel.as_vec2() + 0.5) * scale - 0.5))
+            .collect();
+        Some(heights)
+    }
+}
+
+fn hash(cell: IVec2, seed: u32) -> u32 {
+    let mut h = seed
+        ^ (cell.x as u32).wrapping_mul(0x8da6_b343)
+        ^ (cell.y as u32).wrapping_mul(0xd816_3841);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0x5bd1_e995);
+    h ^ (h >> 15)
+}
+
+/// Gradient noise in about `-1..1`.
+pub(crate) fn gradient_noise(p: Vec2, seed: u32) -> f32 {
+    let cell = p.floor();
+    let f = p - cell;
+    let cell = cell.as_ivec2();
+    let u = f * f * f * (f * (f * 6.0 - 15.0) + 10.0);
+
+    let corner = |offset: IVec2| {
+        let angle = hash(cell + offset, seed) as f32 / u32::MAX as f32 * TAU;
+        Vec2::from_angle(angle).dot(f - offset.as_vec2())
+    };
+    let n00 = corner(IVec2::new(0, 0));
+    let n10 = corner(IVec2::new(1, 0));
+    let n01 = corner(IVec2::new(0, 1));
+    let n11 = corner(IVec2::new(1, 1));
+
+    let nx0 = n00 + (n10 - n00) * u.x;
+    let nx1 = n01 + (n11 - n01) * u.x;
+    (nx0 + (nx1 - nx0) * u.y) * SQRT_2
+}
@@ -0,0 +1,49 @@
+use bevy::{
+    picking::{
+        PickingSystems,
+        backend::{HitData, PointerHits, ray::RayMap},
+    },
+    prelude::*,
+};
+
+use crate::ClipmapRaycast;
+
+/// Picking backend raycasting against clipmap heightfields.
+/// `Pointer` events are fired on the `Clipmap` entity with the world hit position and normal.
+pub struct ClipmapPickingPlugin;
+
+impl Plugin for ClipmapPickingPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(PreUpdate, update_hits.in_set(PickingSystems::Backend));
+    }
+}
+
+fn update_hits(
+    ray_map: Res<RayMap>,
+    cameras: Query<&Camera>,
+    raycast: ClipmapRaycast,
+    mut hits: MessageWriter<PointerHits>,
+) {
+    for (&ray_id, &ray) in ray_map.iter() {
+        let Ok(camera) = cameras.get(ray_id.camera) else {
+            continue;
+        };
+        if !camera.is_active {
+            continue;
+        }
+        let Some((entity, hit)) = raycast.cast_ray(ray, f32::MAX) else {
+            continue;
+        };
+        let data = HitData::new(
+            ray_id.camera,
+            hit.distance,
+            Some(hit.position),
+            Some(hit.normal),
+        );
+        hits.write(PointerHits::new(
+            ray_id.pointer,
+            vec![(entity, data)],
+            camera.order as f32,
+        ));
+    }
+}
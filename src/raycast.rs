@@ -0,0 +1,288 @@
+use bevy::{ecs::system::SystemParam, prelude::*};
+
+use crate::{ClipmapHeightQuery, HeightmapSampler};
+
+/// Number of height tests per texel cell crossed by the ray.
+/// Bilinear height along a line is quadratic, so two tests are enough to not miss thin peaks.
+const CELL_STEPS: u32 = 2;
+
+/// Number of bisection steps used to refine the hit.
+const REFINE_STEPS: u32 = 12;
+
+/// Intersection of a ray with the terrain.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ClipmapRayHit {
+    /// World position of the hit.
+    pub position: Vec3,
+
+    /// World space terrain normal at the hit.
+    pub normal: Vec3,
+
+    /// Distance along the ray.
+    pub distance: f32,
+}
+
+impl HeightmapSampler<'_> {
+    /// Finds the first intersection of the ray with the heightfield.
+    /// Marches the texel cells crossed by the ray (2D DDA), skipping cells whose highest corner is below the ray.
+    pub fn cast_ray(&self, ray: Ray3d, max_distance: f32) -> Option<ClipmapRayHit> {
+        let half_size = self.world_size() / 2.0;
+        let (mut t, t_end) = clip_ray(
+            ray,
+            Vec3::new(-half_size.x, self.denormalize(0.0), -half_size.y),
+            Vec3::new(half_size.x, self.denormalize(1.0), half_size.y),
+            max_distance,
+        )?;
+
+        let above = |t: f32| {
+            let point = ray.get_point(t);
+            point.y - self.height(point.xz())
+        };
+
+        if above(t) <= 0.0 {
+            return (t > 0.0).then(|| self.hit(ray, t));
+        }
+
+        let origin = self.world_to_texel(ray.origin.xz());
+        let dir = ray.direction.xz() / self.texel_size();
+        let step = IVec2::new(
+            if dir.x >= 0.0 { 1 } else { -1 },
+            if dir.y >= 0.0 { 1 } else { -1 },
+        );
+        let t_delta = dir.recip().abs();
+        let mut cell = (origin + dir * t).floor().as_ivec2();
+        let mut t_max = Vec2::new(
+            next_boundary(origin.x, dir.x, cell.x),
+            next_boundary(origin.y, dir.y, cell.y),
+        );
+
+        loop {
+            let t_next = t_max.min_element().min(t_end);
+
+            let cell_max = self.denormalize(
+                [(0, 0), (1, 0), (0, 1), (1, 1)]
+                    .map(|(x, y)| self.texel(cell.x + x, cell.y + y))
+                    .into_iter()
+                    .fold(0.0, f32::max),
+            );
+            let ray_min = ray.get_point(t).y.min(ray.get_point(t_next).y);
+
+            if ray_min <= cell_max {
+                let mut t_prev = t;
+                for i in 1..=CELL_STEPS {
+                    let t_i = t + (t_next - t) * i as f32 / CELL_STEPS as f32;
+                    if above(t_i) <= 0.0 {
+                        return Some(self.refine(ray, t_prev, t_i, above));
+                    }
+                    t_prev = t_i;
+                }
+            }
+
+            if t_next >= t_end {
+                return None;
+            }
+
+            if t_max.x < t_max.y {
+                cell.x += step.x;
+                t_max.x += t_delta.x;
+            } else {
+                cell.y += step.y;
+                t_max.y += t_delta.y;
+            }
+            t = t_next;
+        }
+    }
+
+    fn refine(
+        &self,
+        ray: Ray3d,
+        mut t_above: f32,
+        mut t_below: f32,
+        above: impl Fn(f32) -> f32,
+    ) -> ClipmapRayHit {
+        for _ in 0..REFINE_STEPS {
+            let t = (t_above + t_below) / 2.0;
+            if above(t) > 0.0 {
+                t_above = t;
+            } else {
+                t_below = t;
+            }
+        }
+        self.hit(ray, t_below)
+    }
+
+    fn hit(&self, ray: Ray3d, t: f32) -> ClipmapRayHit {
+        let position = ray.get_point(t);
+        ClipmapRayHit {
+            position,
+            normal: self.normal(position.xz()),
+            distance: t,
+        }
+    }
+}
+
+/// Ray distance to the next cell boundary along one axis in texel space.
+fn next_boundary(origin: f32, dir: f32, cell: i32) -> f32 {
+    if dir > 0.0 {
+        (cell as f32 + 1.0 - origin) / dir
+    } else if dir < 0.0 {
+        (cell as f32 - origin) / dir
+    } else {
+        f32::INFINITY
+    }
+}
+
+/// Clips the ray to the box, returns the entry and exit distances.
+fn clip_ray(ray: Ray3d, min: Vec3, max: Vec3, max_distance: f32) -> Option<(f32, f32)> {
+    let inv_dir = ray.direction.recip();
+    let t0 = (min - ray.origin) * inv_dir;
+    let t1 = (max - ray.origin) * inv_dir;
+    let enter = t0.min(t1).max_element().max(0.0);
+    let exit = t0.max(t1).min_element().min(max_distance);
+    (enter <= exit).then_some((enter, exit))
+}
+
+/// Casts rays against clipmap heightfields on the CPU.
+/// Rays always test the full resolution heightmap, while the outer levels render from coarser mips,
+/// so far from the target a hit can be off the visible surface by the difference between the mips.
+#[derive(SystemParam)]
+pub struct ClipmapRaycast<'w, 's> {
+    heights: ClipmapHeightQuery<'w, 's>,
+}
+
+impl ClipmapRaycast<'_, '_> {
+    /// Closest hit among all clipmaps along with the clipmap entity.
+    pub fn cast_ray(&self, ray: Ray3d, max_distance: f32) -> Option<(Entity, ClipmapRayHit)> {
+        self.heights
+            .iter()
+            .filter_map(|(entity, sampler)| Some((entity, sampler.cast_ray(ray, max_distance)?)))
+            .min_by(|(_, a), (_, b)| a.distance.total_cmp(&b.distance))
+    }
+
+    /// Hit against a single clipmap.
+    pub fn cast_ray_clipmap(
+        &self,
+        clipmap: Entity,
+        ray: Ray3d,
+        max_distance: f32,
+    ) -> Option<ClipmapRayHit> {
+        self.heights.sampler(clipmap)?.cast_ray(ray, max_distance)
+    }
+
+    /// Returns `true` if no terrain lies between two points.
+    pub fn line_of_sight(&self, from: Vec3, to: Vec3) -> bool {
+        let Ok(direction) = Dir3::new(to - from) else {
+            return true;
+        };
+        self.cast_ray(Ray3d::new(from, direction), from.distance(to))
+            .is_none()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::{
+        asset::RenderAssetUsages,
+        render::render_resource::{Extent3d, TextureDimension, TextureFormat},
+    };
+
+    use super::*;
+    use crate::Clipmap;
+
+    /// 16x16 R32Float heightmap centered at the origin, heights from 0 to 10 meters.
+    fn heightmap(height: impl Fn(u32, u32) -> f32) -> (Clipmap, Image) {
+        let size = 16;
+        let data = (0..size * size)
+            .flat_map(|i| height(i % size, i / size).to_le_bytes())
+            .collect();
+        let image = Image::new(
+            Extent3d {
+                width: size,
+                height: size,
+                depth_or_array_layers: 1,
+            },
+            TextureDimension::D2,
+            data,
+            TextureFormat::R32Float,
+            RenderAssetUsages::all(),
+        );
+        let clipmap = Clipmap {
+            min: 0.0,
+            max: 10.0,
+            ..default()
+        };
+        (clipmap, image)
+    }
+
+    #[test]
+    fn vertical_ray_hits_flat_terrain() {
+        let (clipmap, image) = heightmap(|_, _| 0.5);
+        let sampler = HeightmapSampler::new(&clipmap, &image).unwrap();
+        let hit = sampler
+            .cast_ray(Ray3d::new(Vec3::new(1.3, 20.0, -2.7), Dir3::NEG_Y), 100.0)
+            .unwrap();
+        assert!((hit.position.y - 5.0).abs() < 1e-3);
+        assert!((hit.distance - 15.0).abs() < 1e-3);
+        assert!(hit.normal.abs_diff_eq(Vec3::Y, 1e-5));
+    }
+
+    #[test]
+    fn grazing_ray_does_not_miss_thin_peak() {
+        // A single raised texel, the ray passes a bit below its top.
+        let (clipmap, image) = heightmap(|x, y| if (x, y) == (8, 8) { 1.0 } else { 0.0 });
+        let sampler = HeightmapSampler::new(&clipmap, &image).unwrap();
+        let peak = sampler.texel_to_world(Vec2::splat(8.0));
+        let origin = Vec3::new(-7.9, 9.0, -7.7);
+        let target = Vec3::new(peak.x, 9.0, peak.y);
+        let ray = Ray3d::new(origin, Dir3::new(target - origin).unwrap());
+        let hit = sampler.cast_ray(ray, 100.0).unwrap();
+        assert!(hit.position.xz().distance(peak) < 1.0);
+        // The hit is refined to just below the steep flank of the peak.
+        let depth = sampler.height(hit.position.xz()) - hit.position.y;
+        assert!((0.0..0.01).contains(&depth));
+    }
+
+    #[test]
+    fn bisection_refines_onto_slope() {
+        let (clipmap, image) = heightmap(|x, _| x as f32 / 15.0);
+        let sampler = HeightmapSampler::new(&clipmap, &image).unwrap();
+        let origin = Vec3::new(-8.0, 6.0, 0.3);
+        let direction = Dir3::new(Vec3::new(1.0, -0.1, 0.05)).unwrap();
+        let hit = sampler
+            .cast_ray(Ray3d::new(origin, direction), 100.0)
+            .unwrap();
+        assert!((hit.position.y - sampler.height(hit.position.xz())).abs() < 1e-3);
+        assert!(hit.position.distance(origin + direction * hit.distance) < 1e-4);
+        // The slope rises towards +X, so the normal leans towards -X.
+        assert!(hit.normal.x < 0.0 && hit.normal.y > 0.0);
+    }
+
+    #[test]
+    fn rays_miss_outside_and_above() {
+        let (clipmap, image) = heightmap(|_, _| 0.5);
+        let sampler = HeightmapSampler::new(&clipmap, &image).unwrap();
+        // Points away from the terrain.
+        let up = Ray3d::new(Vec3::new(0.0, 20.0, 0.0), Dir3::Y);
+        assert!(sampler.cast_ray(up, 100.0).is_none());
+        // Misses the heightmap bounds.
+        let outside = Ray3d::new(Vec3::new(100.0, 20.0, 0.0), Dir3::NEG_Y);
+        assert!(sampler.cast_ray(outside, 100.0).is_none());
+        // Stops before reaching the surface.
+        let short = Ray3d::new(Vec3::new(0.0, 20.0, 0.0), Dir3::NEG_Y);
+        assert!(sampler.cast_ray(short, 10.0).is_none());
+    }
+
+    #[test]
+    fn dda_boundaries() {
+        assert_eq!(next_boundary(2.25, 0.5, 2), 1.5);
+        assert_eq!(next_boundary(2.25, -0.25, 2), 1.0);
+        assert_eq!(next_boundary(2.25, 0.0, 2), f32::INFINITY);
+
+        let ray = Ray3d::new(Vec3::new(-10.0, 0.5, 0.5), Dir3::X);
+        assert_eq!(
+            clip_ray(ray, Vec3::ZERO, Vec3::ONE, 100.0),
+            Some((10.0, 11.0))
+        );
+        assert_eq!(clip_ray(ray, Vec3::ZERO, Vec3::ONE, 5.0), None);
+    }
+}
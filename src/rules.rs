@@ -0,0 +1,83 @@
+use bevy::{prelude::*, render::render_resource::ShaderType};
+
+/// Maximum number of material rules.
+pub const MAX_RULES: usize = 16;
+
+/// Procedural texturing rules applied on top of the [`ClipmapLayers`](crate::ClipmapLayers) weights.
+/// Rules are applied in order, every rule paints its layer over the result of the previous ones.
+#[derive(Component, Clone, Debug, Default)]
+pub struct ClipmapMaterialRules {
+    /// At most [`MAX_RULES`] rules.
+    pub rules: Vec<MaterialRule>,
+}
+
+/// Paints a terrain layer where the height and the slope are in range.
+#[derive(Clone, Copy, Debug)]
+pub struct MaterialRule {
+    /// Index of the terrain layer.
+    pub layer: u32,
+
+    /// World height range in meters.
+    pub min_height: f32,
+    pub max_height: f32,
+
+    /// Slope range in radians, zero is flat.
+    pub min_slope: f32,
+    pub max_slope: f32,
+
+    /// Distance in meters over which the rule fades out past the height range.
+    pub height_falloff: f32,
+
+    /// Angle in radians over which the rule fades out past the slope range.
+    pub slope_falloff: f32,
+
+    /// Weight of the layer where the rule fully applies.
+    pub strength: f32,
+}
+
+impl Default for MaterialRule {
+    fn default() -> Self {
+        Self {
+            layer: 0,
+            min_height: f32::MIN,
+            max_height: f32::MAX,
+            min_slope: 0.0,
+            max_slope: std::f32::consts::FRAC_PI_2,
+            height_falloff: 0.0,
+            slope_falloff: 0.0,
+            strength: 1.0,
+        }
+    }
+}
+
+#[derive(ShaderType, Reflect, Clone, Copy, Debug, Default)]
+pub(crate) struct GpuMaterialRule {
+    height: Vec2,
+    slope: Vec2,
+    falloff: Vec2,
+    layer: u32,
+    strength: f32,
+}
+
+#[derive(ShaderType, Reflect, Clone, Debug, Default)]
+pub(crate) struct GpuMaterialRules {
+    rules: [GpuMaterialRule; MAX_RULES],
+    pub(crate) count: u32,
+}
+
+impl From<&ClipmapMaterialRules> for GpuMaterialRules {
+    fn from(rules: &ClipmapMaterialRules) -> Self {
+        let mut gpu = Self::default();
+        for (gpu_rule, rule) in gpu.rules.iter_mut().zip(&rules.rules) {
+            *gpu_rule = GpuMaterialRule {
+                height: Vec2::new(rule.min_height, rule.max_height),
+                slope: Vec2::new(rule.min_slope, rule.max_slope),
+                falloff: Vec2::new(rule.height_falloff, rule.slope_falloff),
+                layer: rule.layer,
+                strength: rule.strength,
+            };
+        }
+        gpu.count = rules.rules.len().min(MAX_RULES) as u32;
+        gpu
+    }
+}
@@ -0,0 +1,163 @@
+use std::cmp::Reverse;
+
+use bevy::prelude::*;
+
+use crate::{
+    Clipmap,
+    height::{read_texel, texel_bytes},
+    levels::ClipmapLevels,
+    mip::{build_mips, mip_offset, mip_size},
+    upload::ImageUploads,
+};
+
+/// Resources available to a [`HeightSource`].
+pub struct HeightSourceContext<'a> {
+    pub asset_server: &'a AssetServer,
+    pub images: &'a mut Assets<Image>,
+}
+
+/// Provider of the heights streamed into the per-level textures of a clipmap.
+/// Level 0 has the full resolution, level `n` is downscaled `2^n` times.
+/// Texel `p` of level `n` covers the level 0 texels `p * 2^n..(p + 1) * 2^n`.
+pub trait HeightSource: Send + Sync + 'static {
+    /// Size of the heightmap in level 0 texels, `None` while it is not known yet.
+    fn size(&self, ctx: &HeightSourceContext) -> Option<UVec2>;
+
+    /// Number of levels the source provides.
+    fn levels(&self) -> u32;
+
+    /// Called every frame before `fill` with the resident window of every level.
+    fn update(&mut self, _ctx: &mut HeightSourceContext, _windows: &[Option<IRect>]) {}
+
+    /// Normalized heights of the level region, row by row.
+    /// `None` if the data is not ready yet, the region is requested again next frame.
+    fn fill(&mut self, ctx: &mut HeightSourceContext, level: u32, rect: IRect) -> Option<Vec<f32>>;
+}
+
+/// Streams the heightmap of a clipmap from a [`HeightSource`] instead of `Clipmap::heightmap`.
+/// Regions around the target are requested from the source and copied into per-level textures.
+#[derive(Component)]
+pub struct ClipmapHeightSource {
+    /// Provider of the heights.
+    pub source: Box<dyn HeightSource>,
+
+    /// Width and height of every per-level texture in texels.
+    /// Should be at least four times `Clipmap::half_width`.
+    pub resolution: u32,
+
+    /// Regions are requested from the source in chunks of this size.
+    pub chunk_size: u32,
+
+    /// Most texels requested from the source per frame, at least one chunk is always requested.
+    /// Coarser levels are filled first, they cover the regions waiting for the next frames.
+    pub texel_budget: u32,
+}
+
+impl ClipmapHeightSource {
+    pub fn new(source: impl HeightSource) -> Self {
+        Self {
+            source: Box::new(source),
+            resolution: 512,
+            chunk_size: 64,
+            texel_budget: 64 * 64 * 16,
+        }
+    }
+}
+
+pub(crate) fn update_sources(
+    asset_server: Res<AssetServer>,
+    mut images: ResMut<Assets<Image>>,
+    mut uploads: ResMut<ImageUploads>,
+    transforms: Query<&GlobalTransform>,
+    clipmaps: Query<(&Clipmap, &mut ClipmapHeightSource, &mut ClipmapLevels)>,
+) {
+    for (clipmap, mut source, mut levels) in clipmaps {
+        let mut ctx = HeightSourceContext {
+            asset_server: &asset_server,
+            images: &mut images,
+        };
+        let Some(size) = source.source.size(&ctx) else {
+            continue;
+        };
+        if levels.size != size {
+            levels.resize(size);
+        }
+        let Ok(target) = transforms.get(clipmap.target) else {
+            continue;
+        };
+        let size = size.as_vec2();
+        levels.follow((target.translation().xz() / (size * clipmap.texel_size) + 0.5) * size);
+
+        source.source.update(&mut ctx, &levels.windows());
+        let mut pending = std::mem::take(&mut levels.pending);
+        pending.sort_by_key(|&(level, _)| Reverse(level));
+        let mut filled = 0;
+        for (level, rect) in pending {
+            if filled >= source.texel_budget {
+                levels.pending.push((level, rect));
+                continue;
+            }
+            filled += rect.size().element_product() as u32;
+            let Some(heights) = source.source.fill(&mut ctx, level, rect) else {
+                levels.pending.push((level, rect));
+                continue;
+            };
+            let image = ctx.images.get_mut_untracked(&levels.image).unwrap();
+            levels.write(image, &mut uploads, level, rect, &heights);
+        }
+    }
+}
+
+/// Heights read from the CPU data of an image.
+/// Missing mips are generated once the image is loaded.
+pub struct TextureSource {
+    /// Heightmap texture, must keep its data in the main world.
+    pub image: Handle<Image>,
+
+    /// Number of levels to provide.
+    pub levels: u32,
+}
+
+impl HeightSource for TextureSource {
+    fn size(&self, ctx: &HeightSourceContext) -> Option<UVec2> {
+        let image = ctx.images.get(&self.image)?;
+        image.data.as_ref()?;
+        texel_bytes(image.texture_descriptor.format)?;
+        Some(image.size())
+    }
+
+    fn levels(&self) -> u32 {
+        self.levels
+    }
+
+    fn update(&mut self, ctx: &mut HeightSourceContext, _windows: &[Option<IRect>]) {
+        if ctx.images.get(&self.image).is_some_and(|image| {
+            image.texture_descriptor.mip_level_count == 1
+                && image.data.is_some()
+                && texel_bytes(image.texture_descriptor.format).is_some()
+        }) {
+            build_mips(ctx.images.get_mut(&self.image).unwrap());
+        }
+    }
+
+    fn fill(&mut self, ctx: &mut HeightSourceContext, level: u32, rect: IRect) -> Option<Vec<f32>> {
+        let image = ctx.images.get(&self.image)?;
+        let format = image.texture_descriptor.format;
+        let bytes = texel_bytes(format)?;
+        let data = image.data.as_deref()?;
+        let mip = level.min(image.texture_descriptor.mip_level_count - 1);
+        let size = mip_size(image.size(), mip).as_ivec2();
+        let offset = mip_offset(image.size(), mip, bytes);
+        let scale = 1 << (level - mip);
+
+        let heights = (rect.min.y..rect.max.y)
+            .flat_map(|y| (rect.min.x..rect.max.x).map(move |x| IVec2::new(x, y)))
+            .map(|texel| {
+                let texel = (texel * scale).clamp(IVec2::ZERO, size - 1);
+                let i = offset + (texel.y * size.x + texel.x) as usize * bytes;
+                read_texel(format, &data[i..i + bytes]).unwrap()
+            })
+            .collect();
+        Some(heights)
+    }
+}
@@ -2,21 +2,7 @@
 #import bevy_pbr::pbr_fragment::pbr_input_from_standard_material
 #import bevy_pbr::view_transformations::position_world_to_clip
 
-#import bevy_pbr::{
-    pbr_types,
-    pbr_bindings,
-    mesh_view_bindings as view_bindings,
-    mesh_view_types,
-    lighting,
-    lighting::{LAYER_BASE, LAYER_CLEARCOAT},
-    transmission,
-    clustered_forward as clustering,
-    shadows,
-    ambient,
-    irradiance_volume,
-    mesh_types::{MESH_FLAGS_SHADOW_RECEIVER_BIT, MESH_FLAGS_TRANSMITTED_SHADOW_RECEIVER_BIT},
-}
-#import bevy_render::maths::{E, HALF_PI, powsafe}
+#import bevy_clipmap::lighting::apply_pbr_lighting
 
 #ifdef MESHLET_MESH_MATERIAL_PASS
 #import bevy_pbr::meshlet_visibility_buffer_resolve::VertexOutput
@@ -28,10 +14,6 @@
 #import bevy_pbr::pbr_functions::main_pass_post_lighting_processing
 #endif  // PREPASS_PIPELINE
 
-#ifdef ENVIRONMENT_MAP
-#import bevy_pbr::environment_map
-#endif
-
 #ifdef TONEMAP_IN_SHADER
 #import bevy_core_pipeline::tonemapping::{tone_mapping, screen_space_dither}
 #endif
@@ -40,25 +22,227 @@
 @group(#{MATERIAL_BIND_GROUP}) @binding(101) var color_sampler: sampler;
 @group(#{MATERIAL_BIND_GROUP}) @binding(102) var heightmap_texture: texture_2d<f32>;
 @group(#{MATERIAL_BIND_GROUP}) @binding(103) var heightmap_sampler: sampler;
-@group(#{MATERIAL_BIND_GROUP}) @binding(104) var horizon_texture: texture_2d_array<f32>;
-@group(#{MATERIAL_BIND_GROUP}) @binding(105) var horizon_sampler: sampler;
-@group(#{MATERIAL_BIND_GROUP}) @binding(106) var<uniform> horizon_coeffs: u32;
 @group(#{MATERIAL_BIND_GROUP}) @binding(107) var<uniform> grid_lod: u32;
 @group(#{MATERIAL_BIND_GROUP}) @binding(108) var<uniform> texel_size: f32;
 @group(#{MATERIAL_BIND_GROUP}) @binding(109) var<uniform> minmax: vec2<f32>;
 @group(#{MATERIAL_BIND_GROUP}) @binding(110) var<uniform> translation: vec2<f32>;
 @group(#{MATERIAL_BIND_GROUP}) @binding(111) var<uniform> wireframe: u32;
+@group(#{MATERIAL_BIND_GROUP}) @binding(112) var<uniform> base_scale: f32;
+@group(#{MATERIAL_BIND_GROUP}) @binding(113) var<uniform> half_width: u32;
+@group(#{MATERIAL_BIND_GROUP}) @binding(114) var<uniform> morph_width: f32;
+@group(#{MATERIAL_BIND_GROUP}) @binding(115) var<uniform> target_position: vec2<f32>;
+#ifdef CLIPMAP_LEVELS
+@group(#{MATERIAL_BIND_GROUP}) @binding(116) var levels_texture: texture_2d_array<f32>;
+@group(#{MATERIAL_BIND_GROUP}) @binding(117) var<uniform> levels_size: vec2<u32>;
+#endif
+#ifdef CLIPMAP_LAYERS
+@group(#{MATERIAL_BIND_GROUP}) @binding(118) var control_texture: texture_2d_array<f32>;
+@group(#{MATERIAL_BIND_GROUP}) @binding(119) var control_sampler: sampler;
+@group(#{MATERIAL_BIND_GROUP}) @binding(120) var layer_albedo_texture: texture_2d_array<f32>;
+@group(#{MATERIAL_BIND_GROUP}) @binding(121) var layer_sampler: sampler;
+@group(#{MATERIAL_BIND_GROUP}) @binding(122) var layer_normal_texture: texture_2d_array<f32>;
+@group(#{MATERIAL_BIND_GROUP}) @binding(123) var layer_roughness_texture: texture_2d_array<f32>;
+@group(#{MATERIAL_BIND_GROUP}) @binding(124) var<uniform> layer_tiling: array<vec4<f32>, 4>;
+@group(#{MATERIAL_BIND_GROUP}) @binding(125) var<uniform> layer_count: u32;
+
+struct TerrainSurface {
+    color: vec4<f32>,
+    normal: vec3<f32>,
+    roughness: f32,
+}
+
+#ifdef CLIPMAP_RULES
+struct MaterialRule {
+    height: vec2<f32>,
+    slope: vec2<f32>,
+    falloff: vec2<f32>,
+    layer: u32,
+    strength: f32,
+}
+
+struct MaterialRules {
+    rules: array<MaterialRule, 16>,
+    count: u32,
+}
+
+@group(#{MATERIAL_BIND_GROUP}) @binding(126) var<uniform> material_rules: MaterialRules;
+
+// 1 inside the range, fading to 0 over the falloff outside of it.
+fn range_weight(x: f32, range: vec2<f32>, falloff: f32) -> f32 {
+    let f = max(falloff, 1e-5);
+    return clamp((x - range.x) / f + 1.0, 0.0, 1.0) * clamp((range.y - x) / f + 1.0, 0.0, 1.0);
+}
+
+// Paints the layers of the rules over the splat weights, in order.
+fn apply_rules(weights: ptr<function, array<f32, 16>>, height: f32, normal: vec3<f32>) {
+    let slope = acos(clamp(normal.y, -1.0, 1.0));
+    for (var i = 0u; i < material_rules.count; i++) {
+        let rule = material_rules.rules[i];
+        let weight = rule.strength
+            * range_weight(height, rule.height, rule.falloff.x)
+            * range_weight(slope, rule.slope, rule.falloff.y);
+        for (var layer = 0u; layer < layer_count; layer++) {
+            (*weights)[layer] *= 1.0 - weight;
+        }
+        if rule.layer < layer_count {
+            (*weights)[rule.layer] += weight;
+        }
+    }
+}
+#endif
+
+// Tiled textures of one layer sampled with a single projection.
+fn sample_projection(layer: u32, uv: vec2<f32>, ddx: vec2<f32>, ddy: vec2<f32>) -> TerrainSurface {
+    var out: TerrainSurface;
+    out.color = textureSampleGrad(layer_albedo_texture, layer_sampler, uv, layer, ddx, ddy);
+    out.normal = textureSampleGrad(layer_normal_texture, layer_sampler, uv, layer, ddx, ddy).xyz * 2.0 - 1.0;
+    out.roughness = textureSampleGrad(layer_roughness_texture, layer_sampler, uv, layer, ddx, ddy).r;
+    return out;
+}
+
+// Layer surface with a world space normal.
+fn sample_layer(
+    layer: u32,
+    world_position: vec3<f32>,
+    ddx: vec3<f32>,
+    ddy: vec3<f32>,
+    normal: vec3<f32>,
+) -> TerrainSurface {
+    let tiling = layer_tiling[layer / 4u][layer % 4u];
+    let p = world_position / tiling;
+    let dx = ddx / tiling;
+    let dy = ddy / tiling;
+
+#ifdef CLIPMAP_TRIPLANAR
+    // Projections along every axis blended by the normal, normals use the whiteout blend.
+    var blend = pow(abs(normal), vec3(4.0));
+    blend /= blend.x + blend.y + blend.z;
+
+    var out: TerrainSurface;
+    out.color = vec4(0.0);
+    out.normal = vec3(0.0);
+    out.roughness = 0.0;
+    if blend.x > 0.0 {
+        let x = sample_projection(layer, p.zy, dx.zy, dy.zy);
+        let n = vec3(x.normal.xy + normal.zy, abs(x.normal.z) * normal.x);
+        out.color += blend.x * x.color;
+        out.normal += blend.x * n.zyx;
+        out.roughness += blend.x * x.roughness;
+    }
+    if blend.y > 0.0 {
+        let y = sample_projection(layer, p.xz, dx.xz, dy.xz);
+        let n = vec3(y.normal.xy + normal.xz, abs(y.normal.z) * normal.y);
+        out.color += blend.y * y.color;
+        out.normal += blend.y * n.xzy;
+        out.roughness += blend.y * y.roughness;
+    }
+    if blend.z > 0.0 {
+        let z = sample_projection(layer, p.xy, dx.xy, dy.xy);
+        let n = vec3(z.normal.xy + normal.xy, abs(z.normal.z) * normal.z);
+        out.color += blend.z * z.color;
+        out.normal += blend.z * n;
+        out.roughness += blend.z * z.roughness;
+    }
+    out.normal = normalize(out.normal);
+    return out;
+#else
+    // Tangent frame of the heightfield, U follows world X and V follows world Z.
+    let tangent = normalize(vec3(1.0, -normal.x / normal.y, 0.0));
+    let bitangent = normalize(vec3(0.0, -normal.z / normal.y, 1.0));
+
+    var out = sample_projection(layer, p.xz, dx.xz, dy.xz);
+    out.normal = normalize(tangent * out.normal.x + bitangent * out.normal.y + normal * out.normal.z);
+    return out;
+#endif
+}
+
+// Blends the tiled layers by the weights of the control texture.
+fn sample_layers(uv: vec2<f32>, world_position: vec3<f32>, normal: vec3<f32>) -> TerrainSurface {
+    // Gradients are taken in uniform control flow, so zero weight layers can be skipped.
+    let ddx = dpdx(world_position);
+    let ddy = dpdy(world_position);
+    let fallback = textureSample(color_texture, color_sampler, uv);
+
+    var weights: array<f32, 16>;
+    for (var i = 0u; i < layer_count; i += 4u) {
+        let control = textureSample(control_texture, control_sampler, uv, i / 4u);
+        for (var j = 0u; j < min(4u, layer_count - i); j++) {
+            weights[i + j] = control[j];
+        }
+    }
+#ifdef CLIPMAP_RULES
+    apply_rules(&weights, world_position.y, normal);
+#endif
+
+    var surface: TerrainSurface;
+    surface.color = vec4(0.0);
+    surface.normal = vec3(0.0);
+    surface.roughness = 0.0;
+    var total = 0.0;
+    for (var layer = 0u; layer < layer_count; layer++) {
+        let weight = weights[layer];
+        if weight <= 0.0 {
+            continue;
+        }
+        let layer_surface = sample_layer(layer, world_position, ddx, ddy, normal);
+        surface.color += weight * layer_surface.color;
+        surface.normal += weight * layer_surface.normal;
+        surface.roughness += weight * layer_surface.roughness;
+        total += weight;
+    }
+
+    if total <= 0.0 {
+        surface.color = fallback;
+        surface.normal = normal;
+        surface.roughness = 1.0;
+        return surface;
+    }
+    surface.color /= total;
+    surface.normal = normalize(surface.normal);
+    surface.roughness /= total;
+    return surface;
+}
+#endif
+
+// Size of the heightmap in texels.
+fn heightmap_size(lod: i32) -> vec2<i32> {
+#ifdef CLIPMAP_LEVELS
+    return max(vec2<i32>(levels_size >> vec2(u32(lod))), vec2(1));
+#else
+    return vec2<i32>(textureDimensions(heightmap_texture, lod));
+#endif
+}
+
+fn heightmap_levels() -> i32 {
+#ifdef CLIPMAP_LEVELS
+    return i32(textureNumLayers(levels_texture));
+#else
+    return i32(textureNumLevels(heightmap_texture));
+#endif
+}
+
+fn height_texel(texel: vec2<i32>, lod: i32) -> f32 {
+    let p = clamp(texel, vec2(0), heightmap_size(lod) - 1);
+#ifdef CLIPMAP_LEVELS
+    // Every level is a window of the heightmap wrapped around the texture.
+    let resolution = vec2<i32>(textureDimensions(levels_texture));
+    return textureLoad(levels_texture, ((p % resolution) + resolution) % resolution, lod, 0).r;
+#else
+    return textureLoad(heightmap_texture, p, lod).r;
+#endif
+}
 
 fn height_bilinear(uv: vec2<f32>, lod: i32) -> f32 {
-    let tex_size = vec2<f32>(textureDimensions(heightmap_texture, lod));
-    let pos = uv * tex_size;
+    let tex_size = heightmap_size(lod);
+    // Texels of coarser mips are centered between the texels they were averaged from.
+    let pos = uv * vec2<f32>(tex_size) - 0.5 + 0.5 * exp2(-f32(lod));
     let p0 = vec2<i32>(floor(pos));
     let f = pos - floor(pos);
 
-    let h00 = textureLoad(heightmap_texture, p0, lod).r;
-    let h10 = textureLoad(heightmap_texture, p0 + vec2(1, 0), lod).r;
-    let h01 = textureLoad(heightmap_texture, p0 + vec2(0, 1), lod).r;
-    let h11 = textureLoad(heightmap_texture, p0 + vec2(1, 1), lod).r;
+    let h00 = height_texel(p0, lod);
+    let h10 = height_texel(p0 + vec2(1, 0), lod);
+    let h01 = height_texel(p0 + vec2(0, 1), lod);
+    let h11 = height_texel(p0 + vec2(1, 1), lod);
 
     let hx0 = mix(h00, h10, f.x);
     let hx1 = mix(h01, h11, f.x);
@@ -66,20 +250,107 @@ fn height_bilinear(uv: vec2<f32>, lod: i32) -> f32 {
     return mix(hx0, hx1, f.y);
 }
 
+#ifdef CLIPMAP_LEVELS
+// Whether the texels read by `height_bilinear` are inside the window of the level,
+// which is centered at the target like in `ClipmapLevels::follow`.
+fn level_resident(uv: vec2<f32>, lod: i32) -> bool {
+    let size = vec2<f32>(heightmap_size(0));
+    let target_texel = (target_position / (size * texel_size) + 0.5) * size;
+    let resolution = vec2<i32>(textureDimensions(levels_texture));
+    let origin = vec2<i32>(floor(target_texel * exp2(-f32(lod)))) - resolution / 2;
+
+    let pos = uv * vec2<f32>(heightmap_size(lod)) - 0.5 + 0.5 * exp2(-f32(lod));
+    let last = heightmap_size(lod) - 1;
+    let p0 = clamp(vec2<i32>(floor(pos)), vec2(0), last);
+    let p1 = clamp(vec2<i32>(floor(pos)) + 1, vec2(0), last);
+    return all(p0 >= origin) && all(p1 < origin + resolution);
+}
+#endif
+
+// Filtered height for fragment normals.
+fn height_sample(uv: vec2<f32>) -> f32 {
+#ifdef CLIPMAP_LEVELS
+    // The mip the vertices of the level were built from, or a coarser one where its window ends.
+    let max_lod = heightmap_levels() - 1;
+    var lod = min(i32(height_lod(base_scale * exp2(f32(grid_lod)))), max_lod);
+    while lod < max_lod && !level_resident(uv, lod) {
+        lod += 1;
+    }
+    return height_bilinear(uv, lod);
+#else
+    return textureSample(heightmap_texture, heightmap_sampler, uv).r;
+#endif
+}
+
+// Blends two neighbour mips for fractional lods.
+fn height_trilinear(uv: vec2<f32>, lod: f32) -> f32 {
+    let max_lod = heightmap_levels() - 1;
+    let lod0 = min(i32(floor(lod)), max_lod);
+    let lod1 = min(lod0 + 1, max_lod);
+    return mix(height_bilinear(uv, lod0), height_bilinear(uv, lod1), fract(lod));
+}
+
+// Heightmap mip whose texels match the vertex spacing of the level.
+fn height_lod(grid_scale: f32) -> f32 {
+    return max(log2(grid_scale / texel_size), 0.0);
+}
+
+// Blend factor toward the next coarser level, 1.0 at the outer edge of the level.
+fn morph_factor(world_xz: vec2<f32>, grid_scale: f32) -> f32 {
+    if morph_width <= 0.0 {
+        return 0.0;
+    }
+    let dist = abs(world_xz - target_position) / grid_scale;
+    // Leave room for the snapping offset of the level around the target.
+    let start = f32(half_width) - morph_width - 2.0;
+    let alpha = clamp((dist - start) / morph_width, vec2(0.0), vec2(1.0));
+    return max(alpha.x, alpha.y);
+}
+
+// Moves odd vertices toward their even neighbours, so the level matches the coarser one at alpha = 1.
+fn morph_vertex(world_xz: vec2<f32>, grid_scale: f32, alpha: f32) -> vec2<f32> {
+    var grid_pos = round(world_xz / grid_scale * 2.0) / 2.0;
+    // Stitch vertices between the level vertices belong to the outer edge of the finer level.
+    // The stitch ring lies at `half_width - 2` or further out, where alpha == 1 when morphing is
+    // on. Without morphing they stay between the level vertices to match the odd outer vertices.
+    grid_pos -= fract(grid_pos) * alpha;
+    let odd = grid_pos - 2.0 * floor(grid_pos / 2.0);
+    return (grid_pos - odd * alpha) * grid_scale;
+}
+
 @vertex
 fn vertex(vertex: Vertex, @builtin(vertex_index) idx: u32) -> VertexOutput {
     var out: VertexOutput;
     let model = mesh_functions::get_world_from_local(vertex.instance_index);
     out.world_position = model * vec4<f32>(vertex.position, 1.0);
 
-    let texture_size = vec2<f32>(textureDimensions(heightmap_texture));
+    let grid_scale = base_scale * exp2(f32(grid_lod));
+    let morph = morph_factor(out.world_position.xz, grid_scale);
+    out.world_position = vec4(morph_vertex(out.world_position.xz, grid_scale, morph), 0.0, 1.0).xzyw;
+
+    let texture_size = vec2<f32>(heightmap_size(0));
     let world_size = texel_size * texture_size;
 
     let height_uv = out.world_position.xz / world_size + 0.5;
-    let height = height_bilinear(height_uv, 0);
+    let height = height_trilinear(height_uv, height_lod(grid_scale) + morph);
 
     out.world_position.y = height * (minmax.y - minmax.x) + minmax.x;
     out.position = position_world_to_clip(out.world_position.xyz);
+#ifdef VERTEX_UVS_A
+    out.uv = height_uv;
+#endif
+
+#ifdef PREPASS_PIPELINE
+    // The same vertex path renders the depth prepass and the shadow maps.
+#ifdef UNCLIPPED_DEPTH_ORTHO_EMULATION
+    out.unclipped_depth = out.position.z;
+    out.position.z = min(out.position.z, 1.0);
+#endif
+#ifdef MOTION_VECTOR_PREPASS
+    // The terrain doesn't move, only the grids snap to the camera.
+    out.previous_world_position = out.world_position;
+#endif
+#endif
 
     return out;
 }
@@ -97,546 +368,49 @@ fn fragment(
 
     var in_modified = in;
 
-    let texture_size = vec2<f32>(textureDimensions(heightmap_texture));
+    let texture_size = vec2<f32>(heightmap_size(0));
     let world_size = texture_size * texel_size;
 
     let uv = in.world_position.xz / world_size + 0.5;
     let step = 1.0 / texture_size;
-    let h_r = textureSample(heightmap_texture, heightmap_sampler, uv + vec2(step.x, 0.0)).r;
-    let h_l = textureSample(heightmap_texture, heightmap_sampler, uv - vec2(step.x, 0.0)).r;
-    let h_t = textureSample(heightmap_texture, heightmap_sampler, uv + vec2(0.0, step.y)).r;
-    let h_b = textureSample(heightmap_texture, heightmap_sampler, uv - vec2(0.0, step.y)).r;
+    let h_r = height_sample(uv + vec2(step.x, 0.0));
+    let h_l = height_sample(uv - vec2(step.x, 0.0));
+    let h_t = height_sample(uv + vec2(0.0, step.y));
+    let h_b = height_sample(uv - vec2(0.0, step.y));
 
     let scale = (minmax.y - minmax.x) / (2.0 * texel_size);
     let dh_dx = (h_r - h_l) * scale;
     let dh_dy = (h_t - h_b) * scale;
     in_modified.world_normal = normalize(vec3(-dh_dx, 1.0, -dh_dy));
+    // Textures of the base material span the terrain, the tangent follows world X.
+#ifdef VERTEX_UVS_A
+    in_modified.uv = uv;
+#endif
+#ifdef VERTEX_TANGENTS
+    in_modified.world_tangent = vec4(normalize(vec3(1.0, dh_dx, 0.0)), 1.0);
+#endif
+
+#ifdef CLIPMAP_LAYERS
+    let surface = sample_layers(uv, in.world_position.xyz, in_modified.world_normal);
+    in_modified.world_normal = surface.normal;
+#endif
 
     var pbr_input = pbr_input_from_standard_material(in_modified, is_front);
-    pbr_input.material.perceptual_roughness = 1.0;
-    pbr_input.material.base_color = textureSample(color_texture, color_sampler, uv);
+#ifdef CLIPMAP_LAYERS
+    pbr_input.material.perceptual_roughness *= surface.roughness;
+    pbr_input.material.base_color *= surface.color;
+#else
+    pbr_input.material.base_color *= textureSample(color_texture, color_sampler, uv);
+#endif
 
 #ifdef PREPASS_PIPELINE
     let out = deferred_output(in_modified, pbr_input);
 #else
     var out: FragmentOutput;
-    out.color = apply_pbr_lighting(pbr_input, uv);
+    out.color = apply_pbr_lighting(pbr_input, uv, minmax.y - minmax.x);
     out.color = main_pass_post_lighting_processing(pbr_input, out.color);
 #endif
 
     return out;
 }
 
-fn reconstruct_horizon(uv: vec2<f32>, theta: f32) -> f32 {
-    const N = 360.0;
-
-    var horizon = textureSample(horizon_texture, horizon_sampler, uv, 0).r / N;
-    for (var i = 1u; i <= horizon_coeffs / 2; i++) {
-        let angle = f32(i) * theta;
-        let a = textureSample(horizon_texture, horizon_sampler, uv, i).r;
-        let b = textureSample(horizon_texture, horizon_sampler, uv, i + horizon_coeffs / 2).r;
-        horizon += (2.0 / N) * (a * cos(angle) - b * sin(angle));
-    }
-    horizon *= minmax.y - minmax.x;
-    return clamp(atan(horizon), 0.0, HALF_PI);
-}
-
-fn calculate_diffuse_color(
-    base_color: vec3<f32>,
-    metallic: f32,
-    specular_transmission: f32,
-    diffuse_transmission: f32
-) -> vec3<f32> {
-    return base_color * (1.0 - metallic) * (1.0 - specular_transmission) *
-        (1.0 - diffuse_transmission);
-}
-
-fn calculate_F0(base_color: vec3<f32>, metallic: f32, reflectance: vec3<f32>) -> vec3<f32> {
-    return 0.16 * reflectance * reflectance * (1.0 - metallic) + base_color * metallic;
-}
-
-fn apply_pbr_lighting(
-    in: pbr_types::PbrInput,
-    horizon_uv: vec2<f32>,
-) -> vec4<f32> {
-    var output_color: vec4<f32> = in.material.base_color;
-
-    let emissive = in.material.emissive;
-
-    // calculate non-linear roughness from linear perceptualRoughness
-    let metallic = in.material.metallic;
-    let perceptual_roughness = in.material.perceptual_roughness;
-    let roughness = lighting::perceptualRoughnessToRoughness(perceptual_roughness);
-    let ior = in.material.ior;
-    let thickness = in.material.thickness;
-    let reflectance = in.material.reflectance;
-    let diffuse_transmission = in.material.diffuse_transmission;
-    let specular_transmission = in.material.specular_transmission;
-
-    let specular_transmissive_color = specular_transmission * in.material.base_color.rgb;
-
-    let diffuse_occlusion = in.diffuse_occlusion;
-    let specular_occlusion = in.specular_occlusion;
-
-    // Neubelt and Pettineo 2013, "Crafting a Next-gen Material Pipeline for The Order: 1886"
-    let NdotV = max(dot(in.N, in.V), 0.0001);
-    let R = reflect(-in.V, in.N);
-
-#ifdef STANDARD_MATERIAL_CLEARCOAT
-    // Do the above calculations again for the clearcoat layer. Remember that
-    // the clearcoat can have its own roughness and its own normal.
-    let clearcoat = in.material.clearcoat;
-    let clearcoat_perceptual_roughness = in.material.clearcoat_perceptual_roughness;
-    let clearcoat_roughness = lighting::perceptualRoughnessToRoughness(clearcoat_perceptual_roughness);
-    let clearcoat_N = in.clearcoat_N;
-    let clearcoat_NdotV = max(dot(clearcoat_N, in.V), 0.0001);
-    let clearcoat_R = reflect(-in.V, clearcoat_N);
-#endif  // STANDARD_MATERIAL_CLEARCOAT
-
-    let diffuse_color = calculate_diffuse_color(
-        output_color.rgb,
-        metallic,
-        specular_transmission,
-        diffuse_transmission
-    );
-
-    // Diffuse transmissive strength is inversely related to metallicity and specular transmission, but directly related to diffuse transmission
-    let diffuse_transmissive_color = output_color.rgb * (1.0 - metallic) * (1.0 - specular_transmission) * diffuse_transmission;
-
-    // Calculate the world position of the second Lambertian lobe used for diffuse transmission, by subtracting material thickness
-    let diffuse_transmissive_lobe_world_position = in.world_position - vec4<f32>(in.world_normal, 0.0) * thickness;
-
-    let F0 = calculate_F0(output_color.rgb, metallic, reflectance);
-    let F_ab = lighting::F_AB(perceptual_roughness, NdotV);
-
-    var direct_light: vec3<f32> = vec3<f32>(0.0);
-
-    // Transmitted Light (Specular and Diffuse)
-    var transmitted_light: vec3<f32> = vec3<f32>(0.0);
-
-    // Pack all the values into a structure.
-    var lighting_input: lighting::LightingInput;
-    lighting_input.layers[LAYER_BASE].NdotV = NdotV;
-    lighting_input.layers[LAYER_BASE].N = in.N;
-    lighting_input.layers[LAYER_BASE].R = R;
-    lighting_input.layers[LAYER_BASE].perceptual_roughness = perceptual_roughness;
-    lighting_input.layers[LAYER_BASE].roughness = roughness;
-    lighting_input.P = in.world_position.xyz;
-    lighting_input.V = in.V;
-    lighting_input.diffuse_color = diffuse_color;
-    lighting_input.F0_ = F0;
-    lighting_input.F_ab = F_ab;
-#ifdef STANDARD_MATERIAL_CLEARCOAT
-    lighting_input.layers[LAYER_CLEARCOAT].NdotV = clearcoat_NdotV;
-    lighting_input.layers[LAYER_CLEARCOAT].N = clearcoat_N;
-    lighting_input.layers[LAYER_CLEARCOAT].R = clearcoat_R;
-    lighting_input.layers[LAYER_CLEARCOAT].perceptual_roughness = clearcoat_perceptual_roughness;
-    lighting_input.layers[LAYER_CLEARCOAT].roughness = clearcoat_roughness;
-    lighting_input.clearcoat_strength = clearcoat;
-#endif  // STANDARD_MATERIAL_CLEARCOAT
-#ifdef STANDARD_MATERIAL_ANISOTROPY
-    lighting_input.anisotropy = in.anisotropy_strength;
-    lighting_input.Ta = in.anisotropy_T;
-    lighting_input.Ba = in.anisotropy_B;
-#endif  // STANDARD_MATERIAL_ANISOTROPY
-
-    // And do the same for transmissive if we need to.
-#ifdef STANDARD_MATERIAL_DIFFUSE_TRANSMISSION
-    var transmissive_lighting_input: lighting::LightingInput;
-    transmissive_lighting_input.layers[LAYER_BASE].NdotV = 1.0;
-    transmissive_lighting_input.layers[LAYER_BASE].N = -in.N;
-    transmissive_lighting_input.layers[LAYER_BASE].R = vec3(0.0);
-    transmissive_lighting_input.layers[LAYER_BASE].perceptual_roughness = 1.0;
-    transmissive_lighting_input.layers[LAYER_BASE].roughness = 1.0;
-    transmissive_lighting_input.P = diffuse_transmissive_lobe_world_position.xyz;
-    transmissive_lighting_input.V = -in.V;
-    transmissive_lighting_input.diffuse_color = diffuse_transmissive_color;
-    transmissive_lighting_input.F0_ = vec3(0.0);
-    transmissive_lighting_input.F_ab = vec2(0.1);
-#ifdef STANDARD_MATERIAL_CLEARCOAT
-    transmissive_lighting_input.layers[LAYER_CLEARCOAT].NdotV = 0.0;
-    transmissive_lighting_input.layers[LAYER_CLEARCOAT].N = vec3(0.0);
-    transmissive_lighting_input.layers[LAYER_CLEARCOAT].R = vec3(0.0);
-    transmissive_lighting_input.layers[LAYER_CLEARCOAT].perceptual_roughness = 0.0;
-    transmissive_lighting_input.layers[LAYER_CLEARCOAT].roughness = 0.0;
-    transmissive_lighting_input.clearcoat_strength = 0.0;
-#endif  // STANDARD_MATERIAL_CLEARCOAT
-#ifdef STANDARD_MATERIAL_ANISOTROPY
-    transmissive_lighting_input.anisotropy = in.anisotropy_strength;
-    transmissive_lighting_input.Ta = in.anisotropy_T;
-    transmissive_lighting_input.Ba = in.anisotropy_B;
-#endif  // STANDARD_MATERIAL_ANISOTROPY
-#endif  // STANDARD_MATERIAL_DIFFUSE_TRANSMISSION
-
-    let view_z = dot(vec4<f32>(
-        view_bindings::view.view_from_world[0].z,
-        view_bindings::view.view_from_world[1].z,
-        view_bindings::view.view_from_world[2].z,
-        view_bindings::view.view_from_world[3].z
-    ), in.world_position);
-    let cluster_index = clustering::fragment_cluster_index(in.frag_coord.xy, view_z, in.is_orthographic);
-    var clusterable_object_index_ranges =
-        clustering::unpack_clusterable_object_index_ranges(cluster_index);
-
-    // Point lights (direct)
-    for (var i: u32 = clusterable_object_index_ranges.first_point_light_index_offset;
-            i < clusterable_object_index_ranges.first_spot_light_index_offset;
-            i = i + 1u) {
-        let light_id = clustering::get_clusterable_object_id(i);
-
-        // If we're lightmapped, disable diffuse contribution from the light if
-        // requested, to avoid double-counting light.
-#ifdef LIGHTMAP
-        let enable_diffuse =
-            (view_bindings::clusterable_objects.data[light_id].flags &
-                mesh_view_types::POINT_LIGHT_FLAGS_AFFECTS_LIGHTMAPPED_MESH_DIFFUSE_BIT) != 0u;
-#else   // LIGHTMAP
-        let enable_diffuse = true;
-#endif  // LIGHTMAP
-
-        var shadow: f32 = 1.0;
-        if ((in.flags & MESH_FLAGS_SHADOW_RECEIVER_BIT) != 0u
-                && (view_bindings::clusterable_objects.data[light_id].flags & mesh_view_types::POINT_LIGHT_FLAGS_SHADOWS_ENABLED_BIT) != 0u) {
-            shadow = shadows::fetch_point_shadow(light_id, in.world_position, in.world_normal);
-        }
-
-        let light_contrib = lighting::point_light(light_id, &lighting_input, enable_diffuse, true);
-        direct_light += light_contrib * shadow;
-
-#ifdef STANDARD_MATERIAL_DIFFUSE_TRANSMISSION
-        // NOTE: We use the diffuse transmissive color, the second Lambertian lobe's calculated
-        // world position, inverted normal and view vectors, and the following simplified
-        // values for a fully diffuse transmitted light contribution approximation:
-        //
-        // roughness = 1.0;
-        // NdotV = 1.0;
-        // R = vec3<f32>(0.0) // doesn't really matter
-        // F_ab = vec2<f32>(0.1)
-        // F0 = vec3<f32>(0.0)
-        var transmitted_shadow: f32 = 1.0;
-        if ((in.flags & (MESH_FLAGS_SHADOW_RECEIVER_BIT | MESH_FLAGS_TRANSMITTED_SHADOW_RECEIVER_BIT)) == (MESH_FLAGS_SHADOW_RECEIVER_BIT | MESH_FLAGS_TRANSMITTED_SHADOW_RECEIVER_BIT)
-                && (view_bindings::clusterable_objects.data[light_id].flags & mesh_view_types::POINT_LIGHT_FLAGS_SHADOWS_ENABLED_BIT) != 0u) {
-            transmitted_shadow = shadows::fetch_point_shadow(light_id, diffuse_transmissive_lobe_world_position, -in.world_normal);
-        }
-
-        let transmitted_light_contrib =
-            lighting::point_light(light_id, &transmissive_lighting_input, enable_diffuse, true);
-        transmitted_light += transmitted_light_contrib * transmitted_shadow;
-#endif
-    }
-
-    // Spot lights (direct)
-    for (var i: u32 = clusterable_object_index_ranges.first_spot_light_index_offset;
-            i < clusterable_object_index_ranges.first_reflection_probe_index_offset;
-            i = i + 1u) {
-        let light_id = clustering::get_clusterable_object_id(i);
-
-        // If we're lightmapped, disable diffuse contribution from the light if
-        // requested, to avoid double-counting light.
-#ifdef LIGHTMAP
-        let enable_diffuse =
-            (view_bindings::clusterable_objects.data[light_id].flags &
-                mesh_view_types::POINT_LIGHT_FLAGS_AFFECTS_LIGHTMAPPED_MESH_DIFFUSE_BIT) != 0u;
-#else   // LIGHTMAP
-        let enable_diffuse = true;
-#endif  // LIGHTMAP
-
-        var shadow: f32 = 1.0;
-        if ((in.flags & MESH_FLAGS_SHADOW_RECEIVER_BIT) != 0u
-                && (view_bindings::clusterable_objects.data[light_id].flags &
-                    mesh_view_types::POINT_LIGHT_FLAGS_SHADOWS_ENABLED_BIT) != 0u) {
-            shadow = shadows::fetch_spot_shadow(
-                light_id,
-                in.world_position,
-                in.world_normal,
-                view_bindings::clusterable_objects.data[light_id].shadow_map_near_z,
-            );
-        }
-
-        let light_contrib = lighting::spot_light(light_id, &lighting_input, enable_diffuse);
-        direct_light += light_contrib * shadow;
-
-#ifdef STANDARD_MATERIAL_DIFFUSE_TRANSMISSION
-        // NOTE: We use the diffuse transmissive color, the second Lambertian lobe's calculated
-        // world position, inverted normal and view vectors, and the following simplified
-        // values for a fully diffuse transmitted light contribution approximation:
-        //
-        // roughness = 1.0;
-        // NdotV = 1.0;
-        // R = vec3<f32>(0.0) // doesn't really matter
-        // F_ab = vec2<f32>(0.1)
-        // F0 = vec3<f32>(0.0)
-        var transmitted_shadow: f32 = 1.0;
-        if ((in.flags & (MESH_FLAGS_SHADOW_RECEIVER_BIT | MESH_FLAGS_TRANSMITTED_SHADOW_RECEIVER_BIT)) == (MESH_FLAGS_SHADOW_RECEIVER_BIT | MESH_FLAGS_TRANSMITTED_SHADOW_RECEIVER_BIT)
-                && (view_bindings::clusterable_objects.data[light_id].flags & mesh_view_types::POINT_LIGHT_FLAGS_SHADOWS_ENABLED_BIT) != 0u) {
-            transmitted_shadow = shadows::fetch_spot_shadow(
-                light_id,
-                diffuse_transmissive_lobe_world_position,
-                -in.world_normal,
-                view_bindings::clusterable_objects.data[light_id].shadow_map_near_z,
-            );
-        }
-
-        let transmitted_light_contrib =
-            lighting::spot_light(light_id, &transmissive_lighting_input, enable_diffuse);
-        transmitted_light += transmitted_light_contrib * transmitted_shadow;
-#endif
-    }
-
-    // directional lights (direct)
-    let n_directional_lights = view_bindings::lights.n_directional_lights;
-    for (var i: u32 = 0u; i < n_directional_lights; i = i + 1u) {
-        // check if this light should be skipped, which occurs if this light does not intersect with the view
-        // note point and spot lights aren't skippable, as the relevant lights are filtered in `assign_lights_to_clusters`
-        let light = &view_bindings::lights.directional_lights[i];
-
-        // If we're lightmapped, disable diffuse contribution from the light if
-        // requested, to avoid double-counting light.
-#ifdef LIGHTMAP
-        let enable_diffuse =
-            ((*light).flags &
-                mesh_view_types::DIRECTIONAL_LIGHT_FLAGS_AFFECTS_LIGHTMAPPED_MESH_DIFFUSE_BIT) !=
-                0u;
-#else   // LIGHTMAP
-        let enable_diffuse = true;
-#endif  // LIGHTMAP
-
-        var shadow: f32 = 1.0;
-        if ((in.flags & MESH_FLAGS_SHADOW_RECEIVER_BIT) != 0u
-                && (view_bindings::lights.directional_lights[i].flags & mesh_view_types::DIRECTIONAL_LIGHT_FLAGS_SHADOWS_ENABLED_BIT) != 0u) {
-            shadow = shadows::fetch_directional_shadow(i, in.world_position, in.world_normal, view_z);
-        }
-
-        let horizon_dir = (*light).direction_to_light;
-        let horizon_theta = atan2(horizon_dir.z, horizon_dir.x);
-        let horizon_light_elev = asin(horizon_dir.y);
-        let horizon_max_elev = reconstruct_horizon(horizon_uv, horizon_theta);
-        let horizon_smooth = 0.3;
-        let horizon_shadow = smoothstep(horizon_max_elev, horizon_max_elev + horizon_smooth, horizon_light_elev);
-
-        var light_contrib = lighting::directional_light(i, &lighting_input, enable_diffuse);
-
-#ifdef DIRECTIONAL_LIGHT_SHADOW_MAP_DEBUG_CASCADES
-        light_contrib = shadows::cascade_debug_visualization(light_contrib, i, view_z);
-#endif
-        direct_light += light_contrib * min(shadow, horizon_shadow);
-
-#ifdef STANDARD_MATERIAL_DIFFUSE_TRANSMISSION
-        // NOTE: We use the diffuse transmissive color, the second Lambertian lobe's calculated
-        // world position, inverted normal and view vectors, and the following simplified
-        // values for a fully diffuse transmitted light contribution approximation:
-        //
-        // roughness = 1.0;
-        // NdotV = 1.0;
-        // R = vec3<f32>(0.0) // doesn't really matter
-        // F_ab = vec2<f32>(0.1)
-        // F0 = vec3<f32>(0.0)
-        var transmitted_shadow: f32 = 1.0;
-        if ((in.flags & (MESH_FLAGS_SHADOW_RECEIVER_BIT | MESH_FLAGS_TRANSMITTED_SHADOW_RECEIVER_BIT)) == (MESH_FLAGS_SHADOW_RECEIVER_BIT | MESH_FLAGS_TRANSMITTED_SHADOW_RECEIVER_BIT)
-                && (view_bindings::lights.directional_lights[i].flags & mesh_view_types::DIRECTIONAL_LIGHT_FLAGS_SHADOWS_ENABLED_BIT) != 0u) {
-            transmitted_shadow = shadows::fetch_directional_shadow(i, diffuse_transmissive_lobe_world_position, -in.world_normal, view_z);
-        }
-
-        let transmitted_light_contrib =
-            lighting::directional_light(i, &transmissive_lighting_input, enable_diffuse);
-        transmitted_light += transmitted_light_contrib * transmitted_shadow;
-#endif
-    }
-
-#ifdef STANDARD_MATERIAL_DIFFUSE_TRANSMISSION
-    // NOTE: We use the diffuse transmissive color, the second Lambertian lobe's calculated
-    // world position, inverted normal and view vectors, and the following simplified
-    // values for a fully diffuse transmitted light contribution approximation:
-    //
-    // perceptual_roughness = 1.0;
-    // NdotV = 1.0;
-    // F0 = vec3<f32>(0.0)
-    // diffuse_occlusion = vec3<f32>(1.0)
-    transmitted_light += ambient::ambient_light(diffuse_transmissive_lobe_world_position, -in.N, -in.V, 1.0, diffuse_transmissive_color, vec3<f32>(0.0), 1.0, vec3<f32>(1.0));
-#endif
-
-    // Diffuse indirect lighting can come from a variety of sources. The
-    // priority goes like this:
-    //
-    // 1. Lightmap (highest)
-    // 2. Irradiance volume
-    // 3. Environment map (lowest)
-    //
-    // When we find a source of diffuse indirect lighting, we stop accumulating
-    // any more diffuse indirect light. This avoids double-counting if, for
-    // example, both lightmaps and irradiance volumes are present.
-
-    var indirect_light = vec3(0.0f);
-    var found_diffuse_indirect = false;
-
-#ifdef LIGHTMAP
-    indirect_light += in.lightmap_light * diffuse_color;
-    found_diffuse_indirect = true;
-#endif
-
-#ifdef IRRADIANCE_VOLUME
-    // Irradiance volume light (indirect)
-    if (!found_diffuse_indirect) {
-        let irradiance_volume_light = irradiance_volume::irradiance_volume_light(
-            in.world_position.xyz,
-            in.N,
-            &clusterable_object_index_ranges,
-        );
-        indirect_light += irradiance_volume_light * diffuse_color * diffuse_occlusion;
-        found_diffuse_indirect = true;
-    }
-#endif
-
-    // Environment map light (indirect)
-#ifdef ENVIRONMENT_MAP
-    // If screen space reflections are going to be used for this material, don't
-    // accumulate environment map light yet. The SSR shader will do it.
-#ifdef SCREEN_SPACE_REFLECTIONS
-    let use_ssr = perceptual_roughness <=
-        view_bindings::ssr_settings.perceptual_roughness_threshold;
-#else   // SCREEN_SPACE_REFLECTIONS
-    let use_ssr = false;
-#endif  // SCREEN_SPACE_REFLECTIONS
-
-    if (!use_ssr) {
-#ifdef STANDARD_MATERIAL_ANISOTROPY
-        var bent_normal_lighting_input = lighting_input;
-        bend_normal_for_anisotropy(&bent_normal_lighting_input);
-        let environment_map_lighting_input = &bent_normal_lighting_input;
-#else   // STANDARD_MATERIAL_ANISOTROPY
-        let environment_map_lighting_input = &lighting_input;
-#endif  // STANDARD_MATERIAL_ANISOTROPY
-
-        let environment_light = environment_map::environment_map_light(
-            environment_map_lighting_input,
-            &clusterable_object_index_ranges,
-            found_diffuse_indirect,
-        );
-
-        indirect_light += environment_light.diffuse * diffuse_occlusion +
-            environment_light.specular * specular_occlusion;
-    }
-#endif  // ENVIRONMENT_MAP
-
-    // Ambient light (indirect)
-    // If we are lightmapped, disable the ambient contribution if requested.
-    // This is to avoid double-counting ambient light. (It might be part of the lightmap)
-#ifdef LIGHTMAP
-    let enable_ambient = view_bindings::lights.ambient_light_affects_lightmapped_meshes != 0u;
-#else   // LIGHTMAP
-    let enable_ambient = true;
-#endif  // LIGHTMAP
-    if (enable_ambient) {
-        indirect_light += ambient::ambient_light(in.world_position, in.N, in.V, NdotV, diffuse_color, F0, perceptual_roughness, diffuse_occlusion);
-    }
-
-    // we'll use the specular component of the transmitted environment
-    // light in the call to `specular_transmissive_light()` below
-    var specular_transmitted_environment_light = vec3<f32>(0.0);
-
-#ifdef ENVIRONMENT_MAP
-
-#ifdef STANDARD_MATERIAL_DIFFUSE_OR_SPECULAR_TRANSMISSION
-    // NOTE: We use the diffuse transmissive color, inverted normal and view vectors,
-    // and the following simplified values for the transmitted environment light contribution
-    // approximation:
-    //
-    // diffuse_color = vec3<f32>(1.0) // later we use `diffuse_transmissive_color` and `specular_transmissive_color`
-    // NdotV = 1.0;
-    // R = T // see definition below
-    // F0 = vec3<f32>(1.0)
-    // diffuse_occlusion = 1.0
-    //
-    // (This one is slightly different from the other light types above, because the environment
-    // map light returns both diffuse and specular components separately, and we want to use both)
-
-    let T = -normalize(
-        in.V + // start with view vector at entry point
-        refract(in.V, -in.N, 1.0 / ior) * thickness // add refracted vector scaled by thickness, towards exit point
-    ); // normalize to find exit point view vector
-
-    var transmissive_environment_light_input: lighting::LightingInput;
-    transmissive_environment_light_input.diffuse_color = vec3(1.0);
-    transmissive_environment_light_input.layers[LAYER_BASE].NdotV = 1.0;
-    transmissive_environment_light_input.P = in.world_position.xyz;
-    transmissive_environment_light_input.layers[LAYER_BASE].N = -in.N;
-    transmissive_environment_light_input.V = in.V;
-    transmissive_environment_light_input.layers[LAYER_BASE].R = T;
-    transmissive_environment_light_input.layers[LAYER_BASE].perceptual_roughness = perceptual_roughness;
-    transmissive_environment_light_input.layers[LAYER_BASE].roughness = roughness;
-    transmissive_environment_light_input.F0_ = vec3<f32>(1.0);
-    transmissive_environment_light_input.F_ab = vec2(0.1);
-#ifdef STANDARD_MATERIAL_CLEARCOAT
-    // No clearcoat.
-    transmissive_environment_light_input.clearcoat_strength = 0.0;
-    transmissive_environment_light_input.layers[LAYER_CLEARCOAT].NdotV = 0.0;
-    transmissive_environment_light_input.layers[LAYER_CLEARCOAT].N = in.N;
-    transmissive_environment_light_input.layers[LAYER_CLEARCOAT].R = vec3(0.0);
-    transmissive_environment_light_input.layers[LAYER_CLEARCOAT].perceptual_roughness = 0.0;
-    transmissive_environment_light_input.layers[LAYER_CLEARCOAT].roughness = 0.0;
-#endif  // STANDARD_MATERIAL_CLEARCOAT
-
-    let transmitted_environment_light = environment_map::environment_map_light(
-        &transmissive_environment_light_input,
-        &clusterable_object_index_ranges,
-        false,
-    );
-
-#ifdef STANDARD_MATERIAL_DIFFUSE_TRANSMISSION
-    transmitted_light += transmitted_environment_light.diffuse * diffuse_transmissive_color;
-#endif  // STANDARD_MATERIAL_DIFFUSE_TRANSMISSION
-#ifdef STANDARD_MATERIAL_SPECULAR_TRANSMISSION
-    specular_transmitted_environment_light = transmitted_environment_light.specular * specular_transmissive_color;
-#endif  // STANDARD_MATERIAL_SPECULAR_TRANSMISSION
-
-#endif  // STANDARD_MATERIAL_SPECULAR_OR_DIFFUSE_TRANSMISSION
-
-#endif  // ENVIRONMENT_MAP
-
-    var emissive_light = emissive.rgb * output_color.a;
-
-    // "The clearcoat layer is on top of emission in the layering stack.
-    // Consequently, the emission is darkened by the Fresnel term."
-    //
-    // <https://github.com/KhronosGroup/glTF/blob/main/extensions/2.0/Khronos/KHR_materials_clearcoat/README.md#emission>
-#ifdef STANDARD_MATERIAL_CLEARCOAT
-    emissive_light = emissive_light * (0.04 + (1.0 - 0.04) * pow(1.0 - clearcoat_NdotV, 5.0));
-#endif
-
-    emissive_light = emissive_light * mix(1.0, view_bindings::view.exposure, emissive.a);
-
-#ifdef STANDARD_MATERIAL_SPECULAR_TRANSMISSION
-    transmitted_light += transmission::specular_transmissive_light(in.world_position, in.frag_coord.xyz, view_z, in.N, in.V, F0, ior, thickness, perceptual_roughness, specular_transmissive_color, specular_transmitted_environment_light).rgb;
-
-    if (in.material.flags & pbr_types::STANDARD_MATERIAL_FLAGS_ATTENUATION_ENABLED_BIT) != 0u {
-        // We reuse the `atmospheric_fog()` function here, as it's fundamentally
-        // equivalent to the attenuation that takes place inside the material volume,
-        // and will allow us to eventually hook up subsurface scattering more easily
-        var attenuation_fog: mesh_view_types::Fog;
-        attenuation_fog.base_color.a = 1.0;
-        attenuation_fog.be = pow(1.0 - in.material.attenuation_color.rgb, vec3<f32>(E)) / in.material.attenuation_distance;
-        // TODO: Add the subsurface scattering factor below
-        // attenuation_fog.bi = /* ... */
-        transmitted_light = bevy_pbr::fog::atmospheric_fog(
-            attenuation_fog, vec4<f32>(transmitted_light, 1.0), thickness,
-            vec3<f32>(0.0) // TODO: Pass in (pre-attenuated) scattered light contribution here
-        ).rgb;
-    }
-#endif
-
-    // Total light
-    output_color = vec4<f32>(
-        (view_bindings::view.exposure * (transmitted_light + direct_light + indirect_light)) + emissive_light,
-        output_color.a
-    );
-
-    output_color = clustering::cluster_debug_visualization(
-        output_color,
-        view_z,
-        in.is_orthographic,
-        clusterable_object_index_ranges,
-        cluster_index,
-    );
-
-    return output_color;
-}
\ No newline at end of file
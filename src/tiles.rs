@@ -0,0 +1,142 @@
+use std::collections::{HashMap, HashSet};
+
+use bevy::{
+    asset::{LoadState, RenderAssetUsages},
+    image::ImageLoaderSettings,
+    prelude::*,
+};
+
+use crate::{
+    height::{read_texel, texel_bytes},
+    levels::chunks,
+    source::{HeightSource, HeightSourceContext},
+};
+
+/// Heights loaded from a pyramid of tiles.
+/// Only the tiles under the resident windows are kept loaded.
+pub struct TileSource {
+    /// Asset directory with the tiles stored as `{level}/{x}_{y}.{extension}`.
+    /// Level 0 has the full resolution, every next level halves it.
+    pub directory: String,
+
+    /// Extension of the tile files.
+    pub extension: String,
+
+    /// Size of the full heightmap in level 0 texels.
+    pub size: UVec2,
+
+    /// Width and height of one tile in texels.
+    pub tile_size: u32,
+
+    /// Number of tile levels.
+    pub levels: u32,
+
+    tiles: HashMap<(u32, IVec2), Handle<Image>>,
+}
+
+impl TileSource {
+    pub fn new(
+        directory: impl Into<String>,
+        extension: impl Into<String>,
+        size: UVec2,
+        tile_size: u32,
+        levels: u32,
+    ) -> Self {
+        Self {
+            directory: directory.into(),
+            extension: extension.into(),
+            size,
+            tile_size,
+            levels,
+            tiles: HashMap::new(),
+        }
+    }
+
+    fn load(&mut self, asset_server: &AssetServer, level: u32, tile: IVec2) -> Handle<Image> {
+        self.tiles
+            .entry((level, tile))
+            .or_insert_with(|| {
+                asset_server.load_with_settings(
+                    format!(
+                        "{}/{level}/{}_{}.{}",
+                        self.directory, tile.x, tile.y, self.extension
+                    ),
+                    |settings: &mut ImageLoaderSettings| {
+                        settings.is_srgb = false;
+                        settings.asset_usage = RenderAssetUsages::MAIN_WORLD;
+                    },
+                )
+            })
+            .clone()
+    }
+}
+
+impl HeightSource for TileSource {
+    fn size(&self, _ctx: &HeightSourceContext) -> Option<UVec2> {
+        Some(self.size)
+    }
+
+    fn levels(&self) -> u32 {
+        self.levels
+    }
+
+    fn update(&mut self, ctx: &mut HeightSourceContext, windows: &[Option<IRect>]) {
+        let tile_size = self.tile_size as i32;
+        let needed = windows
+            .iter()
+            .enumerate()
+            .filter_map(|(level, window)| Some((level as u32, (*window)?)))
+            .filter(|(_, window)| !window.is_empty())
+            .flat_map(|(level, window)| chunks(window, tile_size).map(move |chunk| (level, chunk)))
+            .map(|(level, chunk)| (level, chunk.min.div_euclid(IVec2::splat(tile_size))))
+            .collect::<HashSet<_>>();
+
+        self.tiles.retain(|tile, _| needed.contains(tile));
+        for (level, tile) in needed {
+            self.load(ctx.asset_server, level, tile);
+        }
+    }
+
+    fn fill(&mut self, ctx: &mut HeightSourceContext, level: u32, rect: IRect) -> Option<Vec<f32>> {
+        let tile_size = self.tile_size as i32;
+        let width = rect.width() as usize;
+        let mut heights = vec![0.0; rect.size().element_product() as usize];
+        for part in chunks(rect, tile_size) {
+            let tile = part.min.div_euclid(IVec2::splat(tile_size));
+            let handle = self.load(ctx.asset_server, level, tile);
+            let image = match ctx.asset_server.load_state(&handle) {
+                LoadState::Loaded => ctx.images.get(&handle),
+                // Missing tiles are flat.
+                LoadState::Failed(_) => None,
+                _ => return None,
+            };
+            let Some(image) = image else {
+                continue;
+            };
+            let tile_heights = read_tile(image, part, tile * tile_size);
+            for (row, y) in (part.min.y..part.max.y).enumerate() {
+                let start = (y - rect.min.y) as usize * width + (part.min.x - rect.min.x) as usize;
+                let row = &tile_heights[row * part.width() as usize..][..part.width() as usize];
+                heights[start..start + row.len()].copy_from_slice(row);
+            }
+        }
+        Some(heights)
+    }
+}
+
+/// Reads the region of the tile placed at `origin`, row by row.
+fn read_tile(image: &Image, rect: IRect, origin: IVec2) -> Vec<f32> {
+    let format = image.texture_descriptor.format;
+    let (Some(bytes), Some(data)) = (texel_bytes(format), image.data.as_deref()) else {
+        return vec![0.0; rect.size().element_product() as usize];
+    };
+    let size = image.size().as_ivec2();
+    (rect.min.y..rect.max.y)
+        .flat_map(|y| (rect.min.x..rect.max.x).map(move |x| IVec2::new(x, y)))
+        .map(|texel| {
+            let texel = (texel - origin).clamp(IVec2::ZERO, size - 1);
+            let i = (texel.y * size.x + texel.x) as usize * bytes;
+            read_texel(format, &data[i..i + bytes]).unwrap()
+        })
+        .collect()
+}
@@ -0,0 +1,133 @@
+use bevy::{
+    prelude::*,
+    render::{
+        ExtractSchedule, MainWorld, Render, RenderApp, RenderSystems,
+        render_asset::RenderAssets,
+        render_resource::{
+            Extent3d, Origin3d, TexelCopyBufferLayout, TexelCopyTextureInfo, TextureAspect,
+        },
+        renderer::RenderQueue,
+        texture::GpuImage,
+    },
+};
+
+use crate::mip::{mip_size, subresource_offset};
+
+/// Writes regions of images to the GPU without re-uploading whole textures.
+/// The CPU data of the image must already hold the new texels.
+pub(crate) struct UploadPlugin;
+
+impl Plugin for UploadPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ImageUploads>();
+
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+        render_app
+            .init_resource::<ImageUploads>()
+            .add_systems(ExtractSchedule, extract_uploads)
+            .add_systems(
+                Render,
+                write_uploads.in_set(RenderSystems::PrepareResources),
+            );
+    }
+}
+
+struct ImageUpload {
+    image: AssetId<Image>,
+    mip: u32,
+    layer: u32,
+    origin: UVec2,
+    size: UVec2,
+    data: Vec<u8>,
+}
+
+/// Image regions waiting to be written to the GPU.
+#[derive(Resource, Default)]
+pub(crate) struct ImageUploads(Vec<ImageUpload>);
+
+impl ImageUploads {
+    /// Queues the region `min..max` of the image mip and layer for upload.
+    pub(crate) fn push(
+        &mut self,
+        id: impl Into<AssetId<Image>>,
+        image: &Image,
+        mip: u32,
+        layer: u32,
+        min: UVec2,
+        max: UVec2,
+    ) {
+        let Some(data) = image.data.as_ref() else {
+            return;
+        };
+        let Some(bytes) = image.texture_descriptor.format.block_copy_size(None) else {
+            return;
+        };
+        let bytes = bytes as usize;
+        let size = image.size();
+        let level_offset = subresource_offset(image, layer, mip, bytes);
+        let width = mip_size(size, mip).x as usize;
+
+        let region = max.min(mip_size(size, mip)).saturating_sub(min);
+        if region.cmpeq(UVec2::ZERO).any() {
+            return;
+        }
+
+        let mut region_data = Vec::with_capacity(region.element_product() as usize * bytes);
+        for y in min.y..min.y + region.y {
+            let start = level_offset + (y as usize * width + min.x as usize) * bytes;
+            region_data.extend_from_slice(&data[start..start + region.x as usize * bytes]);
+        }
+
+        self.0.push(ImageUpload {
+            image: id.into(),
+            mip,
+            layer,
+            origin: min,
+            size: region,
+            data: region_data,
+        });
+    }
+}
+
+fn extract_uploads(mut main_world: ResMut<MainWorld>, mut uploads: ResMut<ImageUploads>) {
+    uploads.0 = std::mem::take(&mut main_world.resource_mut::<ImageUploads>().0);
+}
+
+fn write_uploads(
+    mut uploads: ResMut<ImageUploads>,
+    gpu_images: Res<RenderAssets<GpuImage>>,
+    queue: Res<RenderQueue>,
+) {
+    for upload in uploads.0.drain(..) {
+        // Not uploaded yet, the whole image will be uploaded from the CPU data.
+        let Some(gpu_image) = gpu_images.get(upload.image) else {
+            continue;
+        };
+        let bytes = upload.data.len() as u32 / upload.size.element_product();
+        queue.write_texture(
+            TexelCopyTextureInfo {
+                texture: &gpu_image.texture,
+                mip_level: upload.mip,
+                origin: Origin3d {
+                    x: upload.origin.x,
+                    y: upload.origin.y,
+                    z: upload.layer,
+                },
+                aspect: TextureAspect::All,
+            },
+            &upload.data,
+            TexelCopyBufferLayout {
+                offset: 0,
+                bytes_per_row: Some(upload.size.x * bytes),
+                rows_per_image: Some(upload.size.y),
+            },
+            Extent3d {
+                width: upload.size.x,
+                height: upload.size.y,
+                depth_or_array_layers: 1,
+            },
+        );
+    }
+}